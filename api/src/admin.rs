@@ -0,0 +1,139 @@
+//! Admin routes for operators to inspect and purge the caches, protected by a token from the
+//! config. The routes don't exist without a token.
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+
+use crate::rate_limit::Bucket;
+use crate::{invalidate_matching, AppError, AppState, ErrorKind};
+
+pub fn admin_router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/cache", get(list_cache).delete(purge_cache))
+        .route("/error-cache", delete(purge_error_cache))
+        .route("/rate-limits", get(list_rate_limits))
+        .route_layer(middleware::from_fn_with_state(state, require_token))
+}
+
+/// Only let through requests with the admin token as a bearer token
+async fn require_token(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(token) = &state.admin_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(provided) if bool::from(provided.as_bytes().ct_eq(token.as_bytes())) => {
+            next.run(request).await
+        }
+        _ => {
+            warn!("Admin request with an invalid token");
+            (StatusCode::UNAUTHORIZED, "Invalid admin token").into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CacheQuery {
+    key: Option<String>,
+    prefix: Option<String>,
+}
+
+/// The cached responses, optionally only the ones with keys starting with `prefix`
+async fn list_cache(
+    State(state): State<AppState>,
+    Query(query): Query<CacheQuery>,
+) -> Json<serde_json::Value> {
+    let prefix = query.prefix.unwrap_or_default();
+    let mut entries: Vec<_> = state
+        .cache
+        .entries()
+        .await
+        .into_iter()
+        .filter(|(key, _)| key.starts_with(&prefix))
+        .map(|(key, cached)| (key, cached.age().as_secs(), cached.content.len()))
+        .collect();
+    entries.sort();
+
+    let entries: Vec<_> = entries
+        .into_iter()
+        .map(|(key, age_secs, size)| json!({"key": key, "age_secs": age_secs, "size_bytes": size}))
+        .collect();
+    Json(json!({ "entries": entries }))
+}
+
+/// Purge a single key, or every key starting with a prefix, from all the caches
+async fn purge_cache(State(state): State<AppState>, Query(query): Query<CacheQuery>) -> Response {
+    let matches: Box<dyn Fn(&str) -> bool + Send + Sync> = match (query.key, query.prefix) {
+        (Some(key), None) => Box::new(move |candidate| candidate == key),
+        (None, Some(prefix)) if !prefix.is_empty() => {
+            Box::new(move |candidate| candidate.starts_with(&prefix))
+        }
+        _ => {
+            return AppError::ValidationError(
+                ErrorKind::Query,
+                "Expected either a `key` or a non-empty `prefix` query parameter".to_string(),
+            )
+            .into_response()
+        }
+    };
+
+    let purged = invalidate_matching(&*state.cache, &matches).await
+        + invalidate_matching(&*state.error_cache, &matches).await
+        + invalidate_matching(&*state.file_cache, &matches).await
+        + invalidate_matching(&*state.default_branches, &matches).await;
+    info!(purged = purged, "Purged cache entries");
+
+    Json(json!({ "purged": purged })).into_response()
+}
+
+async fn purge_error_cache(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let purged = state.error_cache.iter().count();
+    state.error_cache.invalidate_all();
+    info!(purged = purged, "Purged the error cache");
+
+    Json(json!({ "purged": purged }))
+}
+
+async fn list_rate_limits(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let limiter = &state.rate_limiter;
+    let mut entries = limiter.entries();
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let entries: Vec<_> = entries
+        .into_iter()
+        .map(|(key, bucket)| match bucket {
+            Bucket::Window { start, count } => json!({
+                "key": key,
+                "count": count,
+                "window_age_secs": start.elapsed().as_secs(),
+            }),
+            Bucket::Tokens { tokens, updated } => json!({
+                "key": key,
+                "tokens": tokens,
+                "updated_secs_ago": updated.elapsed().as_secs(),
+            }),
+        })
+        .collect();
+
+    Json(json!({
+        "algorithm": limiter.algorithm.name(),
+        "window_secs": limiter.window_size.as_secs(),
+        "max_requests": limiter.max_requests,
+        "entries": entries,
+    }))
+}
@@ -0,0 +1,501 @@
+//! Storage for the cached responses and fetched files.
+//!
+//! The in-memory backend is lost on every restart, which causes a burst of upstream requests
+//! after each deploy. The disk backend keeps the entries in a directory instead, so they survive
+//! restarts. Both are bounded, as the cache keys come from the requests.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use moka::sync::Cache;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+/// Where cached entries are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheBackend {
+    #[default]
+    Memory,
+    Disk,
+}
+
+impl CacheBackend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Memory => "memory",
+            Self::Disk => "disk",
+        }
+    }
+}
+
+impl fmt::Display for CacheBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CacheBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Self::Memory),
+            "disk" => Ok(Self::Disk),
+            _ => Err(format!(
+                "Unknown cache backend {}, expected one of: memory, disk",
+                s
+            )),
+        }
+    }
+}
+
+/// How often the disk backend removes expired entries that haven't been read since expiring
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// A cache of values by key, with entries expiring after a TTL set when the store is created.
+///
+/// Failing to read or write an entry is logged and treated as a miss, as the value can always be
+/// fetched again.
+#[axum::async_trait]
+pub trait CacheStore<V>: Send + Sync {
+    async fn get(&self, key: &str) -> Option<V>;
+
+    async fn insert(&self, key: String, value: V);
+
+    async fn invalidate(&self, key: &str);
+
+    /// Every entry that hasn't expired
+    async fn entries(&self) -> Vec<(String, V)>;
+}
+
+#[axum::async_trait]
+impl<V> CacheStore<V> for Cache<String, V>
+where
+    V: Clone + Send + Sync + 'static,
+{
+    async fn get(&self, key: &str) -> Option<V> {
+        Cache::get(self, key)
+    }
+
+    async fn insert(&self, key: String, value: V) {
+        Cache::insert(self, key, value)
+    }
+
+    async fn invalidate(&self, key: &str) {
+        Cache::invalidate(self, key)
+    }
+
+    async fn entries(&self) -> Vec<(String, V)> {
+        self.iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect()
+    }
+}
+
+/// Build a store of the backend, with entries expiring after `ttl` and at most `max_capacity`
+/// entries. The disk backend keeps the entries in `dir`, and removes expired ones in the
+/// background.
+pub fn build_store<V>(
+    backend: CacheBackend,
+    dir: &Path,
+    ttl: Duration,
+    max_capacity: u64,
+) -> io::Result<Arc<dyn CacheStore<V>>>
+where
+    V: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    Ok(match backend {
+        CacheBackend::Memory => Arc::new(
+            Cache::builder()
+                .time_to_live(ttl)
+                .max_capacity(max_capacity)
+                .build(),
+        ),
+        CacheBackend::Disk => {
+            let store = DiskStore::open(dir, ttl, max_capacity)?;
+            store.spawn_sweeper(SWEEP_INTERVAL);
+            Arc::new(store)
+        }
+    })
+}
+
+/// An entry as stored on disk. The key is kept along with the value, as the file name is a hash
+/// of it.
+#[derive(Serialize, Deserialize)]
+struct StoredEntry<V> {
+    key: String,
+    /// Seconds since the Unix epoch
+    expires_at: u64,
+    value: V,
+}
+
+/// Keeps each entry as a JSON file in a directory. The files are read and written on the
+/// blocking thread pool, so slow disks don't hold up the async workers.
+pub struct DiskStore<V> {
+    dir: Arc<DiskDir>,
+    value: PhantomData<fn() -> V>,
+}
+
+/// The directory of a [`DiskStore`], shared with the blocking tasks using it
+struct DiskDir {
+    path: PathBuf,
+    ttl: Duration,
+    max_entries: u64,
+    /// Roughly how many entries there are, recounted on every sweep
+    len: AtomicU64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl DiskDir {
+    fn path(&self, key: &str) -> PathBuf {
+        let hash = Sha256::digest(key.as_bytes());
+        self.path.join(format!("{}.json", hex::encode(hash)))
+    }
+
+    fn remove(&self, path: &Path) {
+        if fs::remove_file(path).is_ok() {
+            // Can't underflow, the count is only an estimate between sweeps
+            let _ = self
+                .len
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+                    len.checked_sub(1)
+                });
+        }
+    }
+
+    /// Read an entry, removing it if it has expired or can't be read
+    fn read<V: DeserializeOwned>(&self, path: &Path) -> Option<StoredEntry<V>> {
+        let entry = fs::read(path).map_err(|e| e.to_string()).and_then(|bytes| {
+            serde_json::from_slice::<StoredEntry<V>>(&bytes).map_err(|e| e.to_string())
+        });
+
+        match entry {
+            Ok(entry) if entry.expires_at > unix_now() => Some(entry),
+            Ok(_) => {
+                self.remove(path);
+                None
+            }
+            Err(e) => {
+                // Removed between listing and reading, or from a previous version
+                if path.exists() {
+                    warn!(path = %path.display(), error = %e, "Removing unreadable cache entry");
+                    self.remove(path);
+                }
+                None
+            }
+        }
+    }
+
+    fn get<V: DeserializeOwned>(&self, key: &str) -> Option<V> {
+        self.read(&self.path(key))
+            // Guard against hash collisions
+            .filter(|entry: &StoredEntry<V>| entry.key == key)
+            .map(|entry| entry.value)
+    }
+
+    fn insert<V: Serialize>(&self, key: String, value: V) {
+        let path = self.path(&key);
+        let entry = StoredEntry {
+            key,
+            expires_at: unix_now() + self.ttl.as_secs(),
+            value,
+        };
+
+        // Write to a temporary file first, so readers never see a partially written entry
+        let temporary = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+        let is_new = !path.exists();
+        let written = serde_json::to_vec(&entry)
+            .map_err(io::Error::from)
+            .and_then(|bytes| fs::write(&temporary, bytes))
+            .and_then(|_| fs::rename(&temporary, &path));
+        if let Err(e) = written {
+            warn!(key = %entry.key, error = %e, "Failed to write cache entry");
+            let _ = fs::remove_file(&temporary);
+            return;
+        }
+
+        if is_new && self.len.fetch_add(1, Ordering::Relaxed) + 1 > self.max_entries {
+            self.evict();
+        }
+    }
+
+    /// The files in the directory with the given extension, along with when they were modified
+    fn files(&self, extension: &str) -> Vec<(PathBuf, SystemTime)> {
+        let files = match fs::read_dir(&self.path) {
+            Ok(files) => files,
+            Err(e) => {
+                warn!(dir = %self.path.display(), error = %e, "Failed to list cache entries");
+                return Vec::new();
+            }
+        };
+
+        files
+            .filter_map(|file| file.ok())
+            .filter(|file| {
+                file.path()
+                    .extension()
+                    .is_some_and(|file_extension| file_extension == extension)
+            })
+            .filter_map(|file| {
+                let modified = file.metadata().and_then(|metadata| metadata.modified());
+                Some((file.path(), modified.ok()?))
+            })
+            .collect()
+    }
+
+    fn entries<V: DeserializeOwned>(&self) -> Vec<(String, V)> {
+        self.files("json")
+            .into_iter()
+            .filter_map(|(path, _)| self.read(&path))
+            .map(|entry: StoredEntry<V>| (entry.key, entry.value))
+            .collect()
+    }
+
+    /// Remove the least recently written entries, down to nine tenths of the capacity so the
+    /// directory isn't listed on every insert
+    fn evict(&self) {
+        let mut files = self.files("json");
+        files.sort_by_key(|(_, modified)| *modified);
+
+        let target = self.max_entries - self.max_entries / 10;
+        let excess = (files.len() as u64).saturating_sub(target) as usize;
+        for (path, _) in &files[..excess] {
+            self.remove(path);
+        }
+        self.len
+            .store(files.len().min(target as usize) as u64, Ordering::Relaxed);
+        info!(dir = %self.path.display(), evicted = excess, "Evicted cache entries");
+    }
+
+    /// Remove expired and unreadable entries, as well as temporary files left behind by failed
+    /// writes, and recount the entries
+    fn sweep<V: DeserializeOwned>(&self) {
+        let remaining = self.entries::<V>().len();
+        self.len.store(remaining as u64, Ordering::Relaxed);
+
+        for (path, modified) in self.files("tmp") {
+            if modified.elapsed().unwrap_or_default() > SWEEP_INTERVAL {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+impl<V> DiskStore<V>
+where
+    V: Serialize + DeserializeOwned + Send + 'static,
+{
+    /// Open the store in `dir`, creating it if needed and removing expired entries. Once there
+    /// are more than `max_entries`, the oldest are evicted.
+    pub fn open(dir: &Path, ttl: Duration, max_entries: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let dir = DiskDir {
+            path: dir.to_path_buf(),
+            ttl,
+            max_entries,
+            len: AtomicU64::new(0),
+        };
+        dir.sweep::<V>();
+        if dir.len.load(Ordering::Relaxed) > max_entries {
+            dir.evict();
+        }
+
+        Ok(Self {
+            dir: Arc::new(dir),
+            value: PhantomData,
+        })
+    }
+
+    /// Sweep the directory every `interval` while the store is around, when running in a Tokio
+    /// runtime
+    fn spawn_sweeper(&self, interval: Duration) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let dir = Arc::downgrade(&self.dir);
+        runtime.spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            // The first tick is immediate, and the store was just swept when opened
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let Some(dir) = dir.upgrade() else {
+                    return;
+                };
+                let _ = tokio::task::spawn_blocking(move || dir.sweep::<V>()).await;
+            }
+        });
+    }
+
+    /// Run `f` on the blocking thread pool, returning `None` if it panicked
+    async fn blocking<T>(&self, f: impl FnOnce(&DiskDir) -> T + Send + 'static) -> Option<T>
+    where
+        T: Send + 'static,
+    {
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || f(&dir))
+            .await
+            .inspect_err(|e| warn!(error = %e, "Cache operation failed"))
+            .ok()
+    }
+}
+
+#[axum::async_trait]
+impl<V> CacheStore<V> for DiskStore<V>
+where
+    V: Serialize + DeserializeOwned + Send + 'static,
+{
+    async fn get(&self, key: &str) -> Option<V> {
+        let key = key.to_string();
+        self.blocking(move |dir| dir.get(&key)).await.flatten()
+    }
+
+    async fn insert(&self, key: String, value: V) {
+        self.blocking(move |dir| dir.insert(key, value)).await;
+    }
+
+    async fn invalidate(&self, key: &str) {
+        let key = key.to_string();
+        self.blocking(move |dir| dir.remove(&dir.path(&key))).await;
+    }
+
+    async fn entries(&self) -> Vec<(String, V)> {
+        self.blocking(|dir| dir.entries()).await.unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("advent-stars-cache-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_backend_from_str() {
+        assert_eq!("memory".parse(), Ok(CacheBackend::Memory));
+        assert_eq!("disk".parse(), Ok(CacheBackend::Disk));
+        assert!("redis".parse::<CacheBackend>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_disk_store_survives_reopening() {
+        let dir = temporary_dir();
+        let ttl = Duration::from_secs(60);
+
+        let store = DiskStore::<String>::open(&dir, ttl, 100).unwrap();
+        store
+            .insert(
+                "github:user/repo/main/stars.txt".to_string(),
+                "<svg/>".to_string(),
+            )
+            .await;
+        store
+            .insert(
+                "github:user/repo/main/stars.txt#badge".to_string(),
+                "badge".to_string(),
+            )
+            .await;
+        store
+            .invalidate("github:user/repo/main/stars.txt#badge")
+            .await;
+
+        let reopened = DiskStore::<String>::open(&dir, ttl, 100).unwrap();
+        assert_eq!(
+            reopened.get("github:user/repo/main/stars.txt").await,
+            Some("<svg/>".to_string())
+        );
+        assert_eq!(
+            reopened.get("github:user/repo/main/stars.txt#badge").await,
+            None
+        );
+        assert_eq!(
+            reopened.entries().await,
+            vec![(
+                "github:user/repo/main/stars.txt".to_string(),
+                "<svg/>".to_string()
+            )]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disk_store_expiry() {
+        let dir = temporary_dir();
+
+        let store = DiskStore::<String>::open(&dir, Duration::ZERO, 100).unwrap();
+        store.insert("key".to_string(), "value".to_string()).await;
+        assert_eq!(store.get("key").await, None);
+        // Expired entries are removed when read
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // And by the sweeps, without being read
+        store.insert("other".to_string(), "value".to_string()).await;
+        fs::write(dir.join("leftover.tmp"), "").unwrap();
+        let old = SystemTime::now() - SWEEP_INTERVAL * 2;
+        fs::File::options()
+            .write(true)
+            .open(dir.join("leftover.tmp"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        store.dir.sweep::<String>();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // Unreadable entries are misses
+        let store = DiskStore::<String>::open(&dir, Duration::from_secs(60), 100).unwrap();
+        fs::write(store.dir.path("key"), "not json").unwrap();
+        assert_eq!(store.get("key").await, None);
+        assert!(store.entries().await.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disk_store_capacity() {
+        let dir = temporary_dir();
+        let store = DiskStore::<usize>::open(&dir, Duration::from_secs(60), 20).unwrap();
+
+        for i in 0..20 {
+            store.insert(format!("key{}", i), i).await;
+            // Make sure the modification times are ordered
+            let modified = SystemTime::now() - Duration::from_secs(100 - i as u64);
+            fs::File::options()
+                .write(true)
+                .open(store.dir.path(&format!("key{}", i)))
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        assert_eq!(store.entries().await.len(), 20);
+
+        // Going over capacity evicts the oldest, down to nine tenths of the capacity
+        store.insert("key20".to_string(), 20).await;
+        let mut keys: Vec<_> = store.entries().await.into_iter().map(|(_, i)| i).collect();
+        keys.sort();
+        assert_eq!(keys, (3..=20).collect::<Vec<_>>());
+        assert_eq!(store.dir.len.load(Ordering::Relaxed), 18);
+
+        // Overwriting an entry doesn't count as a new one
+        store.insert("key20".to_string(), 21).await;
+        assert_eq!(store.dir.len.load(Ordering::Relaxed), 18);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+//! Working out the IP address of the client, for rate limiting and logs.
+//!
+//! Forwarding headers are only believed when the request comes from a trusted proxy, as anyone
+//! can send them. Without trusted proxies the socket peer address is always used.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+
+/// The header trusted proxies put the client address in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientIpHeader {
+    /// A list of addresses, with each proxy appending the address it got the request from
+    #[default]
+    XForwardedFor,
+    /// The standard `Forwarded` header, using the `for` parameter of each element
+    Forwarded,
+    /// A single address set by the Fly.io proxy
+    FlyClientIp,
+}
+
+impl ClientIpHeader {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::XForwardedFor => "x-forwarded-for",
+            Self::Forwarded => "forwarded",
+            Self::FlyClientIp => "fly-client-ip",
+        }
+    }
+}
+
+impl fmt::Display for ClientIpHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ClientIpHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Ok(Self::XForwardedFor),
+            "forwarded" => Ok(Self::Forwarded),
+            "fly-client-ip" => Ok(Self::FlyClientIp),
+            _ => Err(format!(
+                "Unknown client IP header {}, expected one of: x-forwarded-for, forwarded, fly-client-ip",
+                s
+            )),
+        }
+    }
+}
+
+/// Parse a trusted proxy, either a CIDR range or a single address
+pub fn parse_trusted_proxy(proxy: &str) -> Result<IpNet, String> {
+    proxy
+        .parse::<IpNet>()
+        .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| {
+            format!(
+                "Invalid trusted proxy {}, expected an IP or CIDR range",
+                proxy
+            )
+        })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientIpResolver {
+    header: ClientIpHeader,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl ClientIpResolver {
+    pub fn new(header: ClientIpHeader, trusted_proxies: Vec<IpNet>) -> Self {
+        Self {
+            header,
+            trusted_proxies,
+        }
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// The address of the client, given the socket peer the request came from
+    pub fn resolve(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+
+        let mut values = headers
+            .get_all(self.header.name())
+            .iter()
+            .filter_map(|value| value.to_str().ok());
+
+        let hops: Vec<Option<IpAddr>> = match self.header {
+            ClientIpHeader::FlyClientIp => {
+                return values
+                    .next_back()
+                    .and_then(|value| value.trim().parse().ok())
+                    .unwrap_or(peer);
+            }
+            ClientIpHeader::XForwardedFor => values
+                .flat_map(|value| value.split(','))
+                .map(|hop| parse_hop(hop.trim()))
+                .collect(),
+            ClientIpHeader::Forwarded => values
+                .flat_map(|value| value.split(','))
+                .map(|element| {
+                    element
+                        .split(';')
+                        .filter_map(|pair| pair.split_once('='))
+                        .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                        .and_then(|(_, value)| parse_hop(value.trim().trim_matches('"')))
+                })
+                .collect(),
+        };
+
+        // Each proxy appends the address it got the request from, so walk back from the nearest
+        // hop until reaching one that isn't a trusted proxy. Anything before that could have
+        // been made up by the client.
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = ip;
+                    if !self.is_trusted(&ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// Parse an address from a forwarding header, which may include a port, with IPv6 addresses in
+/// brackets when it does
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            hop.strip_prefix('[')
+                .and_then(|hop| hop.strip_suffix(']'))
+                .and_then(|hop| hop.parse().ok())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const PROXY: &str = "10.0.0.1";
+    const CLIENT: &str = "203.0.113.7";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn resolver(header: ClientIpHeader) -> ClientIpResolver {
+        let trusted = ["10.0.0.0/8", "fd00::/8"]
+            .iter()
+            .map(|proxy| parse_trusted_proxy(proxy).unwrap())
+            .collect();
+        ClientIpResolver::new(header, trusted)
+    }
+
+    #[test]
+    fn test_header_from_str() {
+        assert_eq!("X-Forwarded-For".parse(), Ok(ClientIpHeader::XForwardedFor));
+        assert_eq!("forwarded".parse(), Ok(ClientIpHeader::Forwarded));
+        assert_eq!("Fly-Client-IP".parse(), Ok(ClientIpHeader::FlyClientIp));
+        assert!("x-real-ip".parse::<ClientIpHeader>().is_err());
+
+        assert_eq!(parse_trusted_proxy("10.0.0.1").unwrap().prefix_len(), 32);
+        assert!(parse_trusted_proxy("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn test_untrusted_peer_headers_ignored() {
+        let peer = ip(CLIENT);
+        let spoofed = headers(&[
+            ("x-forwarded-for", "198.51.100.1"),
+            ("forwarded", "for=198.51.100.1"),
+            ("fly-client-ip", "198.51.100.1"),
+        ]);
+
+        for header in [
+            ClientIpHeader::XForwardedFor,
+            ClientIpHeader::Forwarded,
+            ClientIpHeader::FlyClientIp,
+        ] {
+            assert_eq!(resolver(header).resolve(&spoofed, peer), peer);
+        }
+
+        // Without trusted proxies, the peer is always used
+        let resolver = ClientIpResolver::default();
+        assert_eq!(resolver.resolve(&spoofed, ip(PROXY)), ip(PROXY));
+    }
+
+    #[test]
+    fn test_x_forwarded_for() {
+        let resolver = resolver(ClientIpHeader::XForwardedFor);
+        let resolve =
+            |value: &str| resolver.resolve(&headers(&[("x-forwarded-for", value)]), ip(PROXY));
+
+        assert_eq!(resolve(CLIENT), ip(CLIENT));
+        // A client prepending its own entries can't pick its address
+        assert_eq!(resolve(&format!("198.51.100.1, {}", CLIENT)), ip(CLIENT));
+        // Chains of trusted proxies are skipped
+        assert_eq!(
+            resolve(&format!("{}, 10.0.0.2, 10.0.0.3", CLIENT)),
+            ip(CLIENT)
+        );
+        // Garbage stops the walk at the last trusted hop
+        assert_eq!(resolve("not-an-ip, 10.0.0.2"), ip("10.0.0.2"));
+        assert_eq!(resolve("unknown"), ip(PROXY));
+
+        // Multiple headers are combined in order
+        let multiple = headers(&[
+            ("x-forwarded-for", "198.51.100.1"),
+            ("x-forwarded-for", CLIENT),
+        ]);
+        assert_eq!(resolver.resolve(&multiple, ip(PROXY)), ip(CLIENT));
+
+        // Missing headers fall back to the peer
+        assert_eq!(resolver.resolve(&HeaderMap::new(), ip(PROXY)), ip(PROXY));
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let resolver = resolver(ClientIpHeader::Forwarded);
+        let resolve =
+            |value: &str| resolver.resolve(&headers(&[("forwarded", value)]), ip("fd00::1"));
+
+        assert_eq!(resolve(&format!("for={};proto=https", CLIENT)), ip(CLIENT));
+        assert_eq!(
+            resolve(r#"for="[2001:db8::1]:4711", for=10.0.0.2"#),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            resolve(&format!("for=198.51.100.1, for=\"{}:443\"", CLIENT)),
+            ip(CLIENT)
+        );
+        assert_eq!(resolve("for=_hidden"), ip("fd00::1"));
+    }
+
+    #[test]
+    fn test_fly_client_ip() {
+        let resolver = resolver(ClientIpHeader::FlyClientIp);
+
+        let trusted = headers(&[("fly-client-ip", CLIENT)]);
+        assert_eq!(resolver.resolve(&trusted, ip(PROXY)), ip(CLIENT));
+
+        // Other headers are ignored
+        let other = headers(&[("x-forwarded-for", CLIENT)]);
+        assert_eq!(resolver.resolve(&other, ip(PROXY)), ip(PROXY));
+    }
+}
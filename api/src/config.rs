@@ -0,0 +1,566 @@
+//! Configuration of the API, from an optional TOML file with environment variables overriding it.
+//!
+//! Keys in the file are the lowercase names of the environment variables, e.g. `cache_ttl_secs`
+//! for `CACHE_TTL_SECS`, except for the GitHub token which is `github_token` and `GH_PAT`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cache::CacheBackend;
+use crate::client_ip::{parse_trusted_proxy, ClientIpHeader};
+use crate::rate_limit::Algorithm;
+
+/// Shown instead of secrets when printing the config
+const REDACTED: &str = "<redacted>";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid config file {path}: {message}")]
+    Parse { path: PathBuf, message: String },
+    /// A value that can't be used, with the key or environment variable it came from
+    #[error("Invalid {key}: {message}")]
+    Invalid { key: String, message: String },
+}
+
+fn invalid(key: &str, message: impl fmt::Display) -> ConfigError {
+    ConfigError::Invalid {
+        key: key.to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// An invalid value of a key that may have come from the file or the environment, naming both
+fn invalid_setting(key: &str, message: impl fmt::Display) -> ConfigError {
+    invalid(&format!("{} ({})", key, key.to_ascii_uppercase()), message)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub port: u16,
+    pub host: String,
+    pub cache_ttl_secs: u64,
+    pub http_timeout_secs: u64,
+    pub max_cache_size: u64,
+    pub error_cache_ttl_secs: u64,
+    pub file_cache_ttl_secs: u64,
+    pub max_stale_secs: u64,
+    /// How long the resolved default branch of a repository is cached for
+    pub default_branch_ttl_secs: u64,
+    #[serde(with = "from_str")]
+    pub cache_backend: CacheBackend,
+    pub cache_dir: PathBuf,
+    pub rate_limit_window_secs: u64,
+    pub rate_limit_max_requests: u32,
+    #[serde(with = "from_str")]
+    pub rate_limit_algorithm: Algorithm,
+    pub rate_limit_max_entries: u64,
+    #[serde(with = "from_str")]
+    pub client_ip_header: ClientIpHeader,
+    #[serde(with = "trusted_proxies")]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Largest body accepted by `POST /render`, in bytes
+    pub render_max_body_size: usize,
+    /// Requests to `POST /render` allowed per client in each rate limit window
+    pub render_rate_limit_max_requests: u32,
+    /// Largest stars file that will be fetched, in bytes
+    pub max_file_size: u64,
+    /// Sent with every upstream request
+    pub user_agent: String,
+    pub github_token: String,
+    pub github_webhook_secret: String,
+    pub admin_token: String,
+    /// Tokens for private repositories, by `owner` or `owner/repo`, used instead of
+    /// `github_token`
+    pub github_owner_tokens: BTreeMap<String, String>,
+    pub github_api_url: String,
+    pub gitlab_url: String,
+    pub gitea_url: String,
+    pub bitbucket_api_url: String,
+    pub raw_url_allowlist: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 3000,
+            host: "127.0.0.1".to_string(),
+            cache_ttl_secs: 300,
+            http_timeout_secs: 10,
+            max_cache_size: 1000,
+            error_cache_ttl_secs: 60,
+            file_cache_ttl_secs: 86400,
+            max_stale_secs: 3600,
+            default_branch_ttl_secs: 3600,
+            cache_backend: CacheBackend::default(),
+            cache_dir: PathBuf::from("cache"),
+            rate_limit_window_secs: 60,
+            rate_limit_max_requests: 30,
+            rate_limit_algorithm: Algorithm::default(),
+            rate_limit_max_entries: 100000,
+            client_ip_header: ClientIpHeader::default(),
+            trusted_proxies: Vec::new(),
+            render_max_body_size: 16 * 1024,
+            render_rate_limit_max_requests: 60,
+            max_file_size: 1024,
+            user_agent: "AOC-Stars-Generator/0.1.0".to_string(),
+            github_token: String::new(),
+            github_webhook_secret: String::new(),
+            admin_token: String::new(),
+            github_owner_tokens: BTreeMap::new(),
+            github_api_url: "https://api.github.com".to_string(),
+            gitlab_url: "https://gitlab.com".to_string(),
+            gitea_url: "https://codeberg.org".to_string(),
+            bitbucket_api_url: "https://api.bitbucket.org".to_string(),
+            raw_url_allowlist: Vec::new(),
+        }
+    }
+}
+
+/// Override a field with an environment variable, if it's set
+fn override_with<T>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    field: &mut T,
+) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Some(value) = var(name) {
+        *field = value.trim().parse().map_err(|e| invalid(name, e))?;
+    }
+    Ok(())
+}
+
+/// Split a comma separated list, skipping empty items
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+impl Config {
+    /// Load the config file, if any, apply the environment variables on top and validate the
+    /// result
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_toml(&content).map_err(|e| match e {
+            ConfigError::Parse { message, .. } => ConfigError::Parse {
+                path: path.to_path_buf(),
+                message,
+            },
+            e => e,
+        })
+    }
+
+    /// Parse a config file, with invalid values reported with their key
+    fn from_toml(content: &str) -> Result<Self, ConfigError> {
+        serde_path_to_error::deserialize(toml::Deserializer::new(content)).map_err(|e| {
+            let key = e.path().to_string();
+            let error = e.into_inner();
+            if key == "." {
+                // Syntax errors, which the message points at
+                ConfigError::Parse {
+                    path: PathBuf::new(),
+                    message: error.to_string(),
+                }
+            } else {
+                invalid(&key, error.message())
+            }
+        })
+    }
+
+    /// Override the values with the environment variables looked up with `var`
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        override_with(&var, "PORT", &mut self.port)?;
+        override_with(&var, "HOST", &mut self.host)?;
+        override_with(&var, "CACHE_TTL_SECS", &mut self.cache_ttl_secs)?;
+        override_with(&var, "HTTP_TIMEOUT_SECS", &mut self.http_timeout_secs)?;
+        override_with(&var, "MAX_CACHE_SIZE", &mut self.max_cache_size)?;
+        override_with(&var, "ERROR_CACHE_TTL_SECS", &mut self.error_cache_ttl_secs)?;
+        override_with(&var, "FILE_CACHE_TTL_SECS", &mut self.file_cache_ttl_secs)?;
+        override_with(&var, "MAX_STALE_SECS", &mut self.max_stale_secs)?;
+        override_with(
+            &var,
+            "DEFAULT_BRANCH_TTL_SECS",
+            &mut self.default_branch_ttl_secs,
+        )?;
+        override_with(&var, "CACHE_BACKEND", &mut self.cache_backend)?;
+        override_with(&var, "CACHE_DIR", &mut self.cache_dir)?;
+        override_with(
+            &var,
+            "RATE_LIMIT_WINDOW_SECS",
+            &mut self.rate_limit_window_secs,
+        )?;
+        override_with(
+            &var,
+            "RATE_LIMIT_MAX_REQUESTS",
+            &mut self.rate_limit_max_requests,
+        )?;
+        override_with(&var, "RATE_LIMIT_ALGORITHM", &mut self.rate_limit_algorithm)?;
+        override_with(
+            &var,
+            "RATE_LIMIT_MAX_ENTRIES",
+            &mut self.rate_limit_max_entries,
+        )?;
+        override_with(&var, "CLIENT_IP_HEADER", &mut self.client_ip_header)?;
+        if let Some(value) = var("TRUSTED_PROXIES") {
+            self.trusted_proxies = split_list(&value)
+                .map(parse_trusted_proxy)
+                .collect::<Result<_, _>>()
+                .map_err(|e| invalid("TRUSTED_PROXIES", e))?;
+        }
+        override_with(&var, "RENDER_MAX_BODY_SIZE", &mut self.render_max_body_size)?;
+        override_with(
+            &var,
+            "RENDER_RATE_LIMIT_MAX_REQUESTS",
+            &mut self.render_rate_limit_max_requests,
+        )?;
+        override_with(&var, "MAX_FILE_SIZE", &mut self.max_file_size)?;
+        override_with(&var, "USER_AGENT", &mut self.user_agent)?;
+        override_with(&var, "GH_PAT", &mut self.github_token)?;
+        override_with(
+            &var,
+            "GITHUB_WEBHOOK_SECRET",
+            &mut self.github_webhook_secret,
+        )?;
+        override_with(&var, "ADMIN_TOKEN", &mut self.admin_token)?;
+        if let Some(value) = var("GITHUB_OWNER_TOKENS") {
+            self.github_owner_tokens = split_list(&value)
+                .map(|item| {
+                    item.split_once('=')
+                        .map(|(owner, token)| (owner.trim().to_string(), token.trim().to_string()))
+                })
+                .collect::<Option<_>>()
+                // The value is full of tokens, so don't include it in the error
+                .ok_or_else(|| {
+                    invalid(
+                        "GITHUB_OWNER_TOKENS",
+                        "expected a comma separated list of owner=token",
+                    )
+                })?;
+        }
+        override_with(&var, "GITHUB_API_URL", &mut self.github_api_url)?;
+        override_with(&var, "GITLAB_URL", &mut self.gitlab_url)?;
+        override_with(&var, "GITEA_URL", &mut self.gitea_url)?;
+        override_with(&var, "BITBUCKET_API_URL", &mut self.bitbucket_api_url)?;
+        if let Some(value) = var("RAW_URL_ALLOWLIST") {
+            self.raw_url_allowlist = split_list(&value).map(str::to_string).collect();
+        }
+        Ok(())
+    }
+
+    /// Check the values that parse but can't be used. The values have already been merged, so
+    /// errors name both the key in the file and the environment variable.
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.host
+            .parse::<IpAddr>()
+            .map_err(|_| invalid_setting("host", format!("{} is not an IP address", self.host)))?;
+
+        for (key, value) in [
+            ("http_timeout_secs", self.http_timeout_secs),
+            ("max_cache_size", self.max_cache_size),
+            ("rate_limit_window_secs", self.rate_limit_window_secs),
+            ("rate_limit_max_entries", self.rate_limit_max_entries),
+            ("render_max_body_size", self.render_max_body_size as u64),
+            ("max_file_size", self.max_file_size),
+        ] {
+            if value == 0 {
+                return Err(invalid_setting(key, "must be greater than 0"));
+            }
+        }
+
+        if self.user_agent.trim().is_empty() {
+            return Err(invalid_setting("user_agent", "must not be empty"));
+        }
+        if self.cache_backend == CacheBackend::Disk && self.cache_dir.as_os_str().is_empty() {
+            return Err(invalid_setting(
+                "cache_dir",
+                "must be set for the disk cache backend",
+            ));
+        }
+
+        for (owner, token) in &self.github_owner_tokens {
+            let parts: Vec<_> = owner.split('/').collect();
+            if parts.len() > 2 || parts.iter().any(|part| part.trim().is_empty()) {
+                return Err(invalid_setting(
+                    "github_owner_tokens",
+                    format!("{} is not an owner or owner/repo", owner),
+                ));
+            }
+            if token.trim().is_empty() {
+                return Err(invalid_setting(
+                    "github_owner_tokens",
+                    format!("the token for {} is empty", owner),
+                ));
+            }
+        }
+
+        for (key, url) in [
+            ("github_api_url", &self.github_api_url),
+            ("gitlab_url", &self.gitlab_url),
+            ("gitea_url", &self.gitea_url),
+            ("bitbucket_api_url", &self.bitbucket_api_url),
+        ] {
+            reqwest::Url::parse(url)
+                .map_err(|e| invalid_setting(key, format!("{}: {}", url, e)))?;
+        }
+
+        Ok(())
+    }
+
+    /// The config as TOML, with the secrets redacted
+    pub fn to_redacted_toml(&self) -> String {
+        let redact = |secret: &str| {
+            if secret.is_empty() {
+                String::new()
+            } else {
+                REDACTED.to_string()
+            }
+        };
+        let redacted = Self {
+            github_token: redact(&self.github_token),
+            github_webhook_secret: redact(&self.github_webhook_secret),
+            admin_token: redact(&self.admin_token),
+            github_owner_tokens: self
+                .github_owner_tokens
+                .iter()
+                .map(|(owner, token)| (owner.clone(), redact(token)))
+                .collect(),
+            ..self.clone()
+        };
+        toml::to_string(&redacted).expect("config can be serialized")
+    }
+}
+
+/// (De)serialize a value as a string, through `Display` and `FromStr`
+mod from_str {
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Trusted proxies are either CIDR ranges or single addresses
+mod trusted_proxies {
+    use ipnet::IpNet;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use crate::client_ip::parse_trusted_proxy;
+
+    pub fn serialize<S: Serializer>(proxies: &[IpNet], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(proxies.iter().map(|proxy| proxy.to_string()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|proxy| parse_trusted_proxy(proxy))
+            .collect::<Result<_, _>>()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    fn error_message(result: Result<impl fmt::Debug, ConfigError>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_file_with_env_overrides() {
+        let mut config = Config::from_toml(
+            r#"
+            port = 8080
+            cache_backend = "disk"
+            rate_limit_algorithm = "token-bucket"
+            trusted_proxies = ["10.0.0.0/8", "192.168.1.1"]
+            max_file_size = 4096
+            user_agent = "my-stars/1.0"
+            "#,
+        )
+        .unwrap();
+        config
+            .apply_env(env(&[
+                ("PORT", "9000"),
+                ("RAW_URL_ALLOWLIST", "example.com, other.org"),
+            ]))
+            .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.cache_backend, CacheBackend::Disk);
+        assert_eq!(config.rate_limit_algorithm, Algorithm::TokenBucket);
+        assert_eq!(config.trusted_proxies.len(), 2);
+        assert_eq!(config.max_file_size, 4096);
+        assert_eq!(config.user_agent, "my-stars/1.0");
+        assert_eq!(config.raw_url_allowlist, vec!["example.com", "other.org"]);
+        // Missing keys get the defaults
+        assert_eq!(config.cache_ttl_secs, Config::default().cache_ttl_secs);
+    }
+
+    #[test]
+    fn test_errors_name_the_key() {
+        let mut config = Config::default();
+        let message = error_message(config.apply_env(env(&[("CACHE_TTL_SECS", "5m")])));
+        assert!(
+            message.starts_with("Invalid CACHE_TTL_SECS:"),
+            "{}",
+            message
+        );
+
+        let message = error_message(config.apply_env(env(&[("TRUSTED_PROXIES", "10.0.0.0/33")])));
+        assert!(
+            message.starts_with("Invalid TRUSTED_PROXIES:"),
+            "{}",
+            message
+        );
+
+        let message = error_message(
+            config.apply_env(env(&[("GITHUB_OWNER_TOKENS", "user=ghp_first,ghp_second")])),
+        );
+        assert!(
+            message.starts_with("Invalid GITHUB_OWNER_TOKENS:") && !message.contains("ghp_"),
+            "{}",
+            message
+        );
+        config
+            .apply_env(env(&[(
+                "GITHUB_OWNER_TOKENS",
+                "user=ghp_first, org/repo=ghp_second",
+            )]))
+            .unwrap();
+        assert_eq!(config.github_owner_tokens["org/repo"], "ghp_second");
+        config.validate().unwrap();
+
+        let config = Config {
+            github_owner_tokens: BTreeMap::from([("user/repo/path".to_string(), "t".to_string())]),
+            ..Config::default()
+        };
+        assert!(error_message(config.validate())
+            .starts_with("Invalid github_owner_tokens (GITHUB_OWNER_TOKENS):"));
+
+        let config = Config {
+            max_file_size: 0,
+            ..Config::default()
+        };
+        assert_eq!(
+            error_message(config.validate()),
+            "Invalid max_file_size (MAX_FILE_SIZE): must be greater than 0"
+        );
+
+        let config = Config {
+            github_api_url: "not a url".to_string(),
+            ..Config::default()
+        };
+        assert!(error_message(config.validate())
+            .starts_with("Invalid github_api_url (GITHUB_API_URL):"));
+
+        // Values set through the environment are reported with their variable as well
+        let mut config = Config::default();
+        config.apply_env(env(&[("MAX_FILE_SIZE", "0")])).unwrap();
+        assert!(error_message(config.validate()).contains("MAX_FILE_SIZE"));
+
+        assert_eq!(
+            error_message(Config::from_toml("cache_ttl_secs = \"5m\"")),
+            "Invalid cache_ttl_secs: invalid type: string \"5m\", expected u64"
+        );
+        let message = error_message(Config::from_toml("rate_limit_algorithm = \"leaky\""));
+        assert!(
+            message.starts_with("Invalid rate_limit_algorithm: Unknown rate limit algorithm"),
+            "{}",
+            message
+        );
+        let message = error_message(Config::from_toml(
+            "trusted_proxies = [\"10.0.0.1\", \"nope\"]",
+        ));
+        assert!(
+            message.starts_with("Invalid trusted_proxies:"),
+            "{}",
+            message
+        );
+        let message = error_message(Config::from_toml("unknown = 1"));
+        assert!(message.contains("unknown field `unknown`"), "{}", message);
+    }
+
+    #[test]
+    fn test_redacted_toml() {
+        let config = Config {
+            github_token: "ghp_secret".to_string(),
+            admin_token: "admin-secret".to_string(),
+            github_owner_tokens: BTreeMap::from([("user".to_string(), "ghp_owner".to_string())]),
+            trusted_proxies: vec![parse_trusted_proxy("10.0.0.1").unwrap()],
+            ..Config::default()
+        };
+
+        let printed = config.to_redacted_toml();
+        assert!(!printed.contains("ghp_secret"), "{}", printed);
+        assert!(!printed.contains("admin-secret"), "{}", printed);
+        assert!(!printed.contains("ghp_owner"), "{}", printed);
+        assert!(printed.contains(r#"github_token = "<redacted>""#));
+        assert!(printed.contains(r#"github_webhook_secret = """#));
+
+        // The printed config can be loaded again
+        let loaded: Config = toml::from_str(&printed).unwrap();
+        assert_eq!(loaded.trusted_proxies, config.trusted_proxies);
+        assert_eq!(loaded.port, config.port);
+        assert!(loaded.github_owner_tokens.contains_key("user"));
+    }
+}
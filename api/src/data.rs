@@ -0,0 +1,94 @@
+//! The parsed stars and their stats as JSON, for building dashboards from the same files as the
+//! rendered grids.
+
+use serde::Serialize;
+use svg::Years;
+
+/// Each day has two stars
+const STARS_PER_DAY: usize = 2;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct YearStats {
+    pub year: usize,
+    /// The stars for each day, from the 1st
+    pub days: Vec<u8>,
+    pub stars: usize,
+    pub max_stars: usize,
+    pub completion_percent: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StarsData {
+    /// Oldest year first
+    pub years: Vec<YearStats>,
+    pub total_stars: usize,
+    pub max_stars: usize,
+    pub completion_percent: f64,
+}
+
+/// The percentage of stars collected, rounded to one decimal
+fn completion_percent(stars: usize, max_stars: usize) -> f64 {
+    if max_stars == 0 {
+        return 0.0;
+    }
+    (stars as f64 * 1000.0 / max_stars as f64).round() / 10.0
+}
+
+impl StarsData {
+    pub fn new(mut years: Years) -> Self {
+        years.sort_by_key(|(year, _)| *year);
+
+        let years: Vec<_> = years
+            .into_iter()
+            .map(|(year, days)| {
+                let stars = days.iter().map(|&stars| stars as usize).sum();
+                let max_stars = days.len() * STARS_PER_DAY;
+                YearStats {
+                    year,
+                    days,
+                    stars,
+                    max_stars,
+                    completion_percent: completion_percent(stars, max_stars),
+                }
+            })
+            .collect();
+
+        let total_stars = years.iter().map(|year| year.stars).sum();
+        let max_stars = years.iter().map(|year| year.max_stars).sum();
+        Self {
+            years,
+            total_stars,
+            max_stars,
+            completion_percent: completion_percent(total_stars, max_stars),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stars_data() {
+        let mut complete = vec![2; 25];
+        complete[24] = 1;
+        let years = vec![(2024, vec![2, 1, 0]), (2015, complete)];
+
+        let data = StarsData::new(years);
+        assert_eq!(
+            data.years.iter().map(|year| year.year).collect::<Vec<_>>(),
+            vec![2015, 2024]
+        );
+        assert_eq!(data.years[0].stars, 49);
+        assert_eq!(data.years[0].max_stars, 50);
+        assert_eq!(data.years[0].completion_percent, 98.0);
+        assert_eq!(data.years[1].completion_percent, 50.0);
+        assert_eq!(data.total_stars, 52);
+        assert_eq!(data.max_stars, 56);
+        assert_eq!(data.completion_percent, 92.9);
+
+        let empty = StarsData::new(Vec::new());
+        assert_eq!(empty.total_stars, 0);
+        assert_eq!(empty.completion_percent, 0.0);
+    }
+}
@@ -0,0 +1,230 @@
+//! Rendering of error responses as images, so embeds show what went wrong instead of a broken
+//! image.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{
+        header::{ACCEPT, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, VARY},
+        HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::Response,
+};
+use svg::generate_error_svg;
+
+use crate::ErrorKind;
+
+// Error bodies are short messages, anything bigger isn't one of ours
+const MAX_ERROR_BODY: usize = 4096;
+
+fn error_title(status: StatusCode, kind: ErrorKind) -> &'static str {
+    match (status, kind) {
+        (StatusCode::NOT_FOUND, ErrorKind::File) => "Stars file not found",
+        (StatusCode::NOT_FOUND, _) => "Not available",
+        (StatusCode::PAYLOAD_TOO_LARGE, _) => "Stars file too big",
+        (StatusCode::BAD_REQUEST, ErrorKind::Query) => "Invalid query parameter",
+        (StatusCode::BAD_REQUEST, ErrorKind::Path) => "Invalid request",
+        (StatusCode::BAD_REQUEST, ErrorKind::File) => "Invalid stars file",
+        (StatusCode::TOO_MANY_REQUESTS, _) => "Rate limit exceeded",
+        _ => "Unable to load stars",
+    }
+}
+
+fn header_contains(value: Option<&HeaderValue>, needle: &str) -> bool {
+    value
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains(needle))
+}
+
+/// Replace the bodies of errors loading stars with an SVG explaining the error when the client
+/// accepts images, keeping the original status code. Other responses, like the admin routes
+/// refusing a token, are left alone.
+pub async fn render_error_images(request: Request, next: Next) -> Response {
+    let accepts_images = header_contains(request.headers().get(ACCEPT), "image/");
+    let mut response = next.run(request).await;
+
+    let status = response.status();
+    let Some(&kind) = response.extensions().get::<ErrorKind>() else {
+        return response;
+    };
+    if !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    // Either variant can be served for the same URL, so caches have to keep them apart
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("Accept"));
+    if !accepts_images {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let message = match axum::body::to_bytes(body, MAX_ERROR_BODY).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => String::new(),
+    };
+
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static("image/svg+xml"));
+    parts
+        .headers
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+
+    Response::from_parts(
+        parts,
+        Body::from(generate_error_svg(error_title(status, kind), &message)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, response::IntoResponse, routing::get, Router};
+    use tower::ServiceExt;
+
+    use crate::params::parse_render_options;
+    use crate::AppError;
+
+    fn app() -> Router {
+        Router::new()
+            .route(
+                "/missing",
+                get(|| async {
+                    AppError::NotFound(ErrorKind::File, "File not found: stars.txt".to_string())
+                }),
+            )
+            .route(
+                "/unknown-param",
+                get(|| async {
+                    parse_render_options(&[("unknown".to_string(), "1".to_string())]).unwrap_err()
+                }),
+            )
+            .route(
+                "/host-not-allowed",
+                get(|| async {
+                    AppError::NotFound(ErrorKind::Query, "Host not allowed: x.org".to_string())
+                }),
+            )
+            .route(
+                "/unauthorized",
+                get(|| async { (StatusCode::UNAUTHORIZED, "Invalid token") }),
+            )
+            .route(
+                "/json-error",
+                get(|| async {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        [(CONTENT_TYPE, "application/json")],
+                        "{}",
+                    )
+                        .into_response()
+                }),
+            )
+            .route("/ok", get(|| async { "fine" }))
+            .layer(middleware::from_fn(render_error_images))
+    }
+
+    async fn send_with_accept(path: &str, accept: &str) -> Response {
+        let request = Request::builder()
+            .uri(path)
+            .header(ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap();
+        app().oneshot(request).await.unwrap()
+    }
+
+    async fn get_with_accept(path: &str, accept: &str) -> (StatusCode, String, String) {
+        let response = send_with_accept(path, accept).await;
+        let status = response.status();
+        let content_type = response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_error_rendered_as_image() {
+        let (status, content_type, body) =
+            get_with_accept("/missing", "image/webp,image/svg+xml,*/*").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(content_type, "image/svg+xml");
+        assert!(body.contains("Stars file not found"));
+        assert!(body.contains("File not found: stars.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_error_kept_as_text() {
+        let (status, content_type, body) = get_with_accept("/missing", "text/html").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(content_type.starts_with("text/plain"));
+        assert_eq!(body, "File not found: stars.txt");
+    }
+
+    #[tokio::test]
+    async fn test_error_varies_on_accept() {
+        for accept in ["image/*", "text/html"] {
+            let response = send_with_accept("/missing", accept).await;
+            assert_eq!(response.headers()[VARY], "Accept");
+        }
+
+        let response = send_with_accept("/ok", "image/*").await;
+        assert!(!response.headers().contains_key(VARY));
+    }
+
+    #[tokio::test]
+    async fn test_error_titles() {
+        let (status, _, body) = get_with_accept("/unknown-param", "image/*").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("Invalid query parameter"), "{}", body);
+        assert!(body.contains("Unknown query parameter"), "{}", body);
+
+        let (status, _, body) = get_with_accept("/host-not-allowed", "image/*").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("Not available"), "{}", body);
+        assert!(!body.contains("Stars file not found"), "{}", body);
+    }
+
+    #[test]
+    fn test_error_title() {
+        let title = |kind| error_title(StatusCode::BAD_REQUEST, kind);
+        assert_eq!(title(ErrorKind::Query), "Invalid query parameter");
+        assert_eq!(title(ErrorKind::Path), "Invalid request");
+        assert_eq!(title(ErrorKind::File), "Invalid stars file");
+        assert_eq!(
+            error_title(StatusCode::NOT_FOUND, ErrorKind::File),
+            "Stars file not found"
+        );
+        assert_eq!(
+            error_title(StatusCode::INTERNAL_SERVER_ERROR, ErrorKind::File),
+            "Unable to load stars"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_other_responses_untouched() {
+        let (_, content_type, _) = get_with_accept("/json-error", "image/*").await;
+        assert_eq!(content_type, "application/json");
+
+        let (status, _, body) = get_with_accept("/ok", "image/*").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "fine");
+
+        // Errors that aren't about loading stars, like the admin routes refusing a token
+        let (status, content_type, body) = get_with_accept("/unauthorized", "image/*").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(content_type.starts_with("text/plain"));
+        assert_eq!(body, "Invalid token");
+    }
+}
@@ -0,0 +1,67 @@
+//! Entity tags for our responses, so clients can revalidate their copy instead of downloading it
+//! again.
+
+use axum::http::{header::IF_NONE_MATCH, HeaderMap};
+use sha2::{Digest, Sha256};
+
+/// A strong `ETag` for the content, derived from its hash so it's stable across restarts
+pub fn content_etag(content: &str) -> String {
+    let digest = Sha256::digest(content.as_bytes());
+    let hex: String = digest[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("\"{}\"", hex)
+}
+
+/// Whether the `If-None-Match` header of the request matches the `ETag`, meaning the client
+/// already has the current content. `If-None-Match` uses the weak comparison, so `W/` prefixes
+/// are ignored.
+pub fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = strip_weak(etag);
+
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || strip_weak(tag) == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(if_none_match: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_str(if_none_match).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_content_etag() {
+        let etag = content_etag("<svg></svg>");
+        assert_eq!(etag, content_etag("<svg></svg>"));
+        assert_ne!(etag, content_etag("<svg/>"));
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_eq!(etag.len(), 34);
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let etag = content_etag("<svg></svg>");
+
+        assert!(if_none_match(&headers(&etag), &etag));
+        assert!(if_none_match(&headers(&format!("W/{}", etag)), &etag));
+        assert!(if_none_match(
+            &headers(&format!("\"other\", {}", etag)),
+            &etag
+        ));
+        assert!(if_none_match(&headers("*"), &etag));
+
+        assert!(!if_none_match(&headers("\"other\""), &etag));
+        assert!(!if_none_match(&HeaderMap::new(), &etag));
+    }
+}
@@ -0,0 +1,1951 @@
+//! API serving Advent of Code stars files from GitHub and other sources as SVGs, badges and
+//! JSON, with caching and rate limiting in front of the upstreams.
+//!
+//! The `api` binary loads the [`Config`](config::Config) and serves [`build_router`].
+
+mod admin;
+mod cache;
+mod client_ip;
+pub mod config;
+mod data;
+mod error_image;
+mod etag;
+mod metrics;
+mod params;
+mod rate_limit;
+mod render;
+mod single_flight;
+mod sources;
+mod webhook;
+
+use admin::admin_router;
+use axum::{
+    extract::{rejection::QueryRejection, ConnectInfo, FromRef, FromRequestParts, Path, Query},
+    http::{request::Parts, HeaderMap, HeaderValue, Method},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post, Router},
+};
+use cache::{build_store, CacheBackend, CacheStore};
+use client_ip::ClientIpResolver;
+use config::Config;
+use data::StarsData;
+use error_image::render_error_images;
+use etag::{content_etag, if_none_match};
+use metrics::{handle_metrics, track_requests, CacheKind, CacheResult, Metrics};
+use moka::sync::Cache;
+use params::{parse_render_options, render_variant};
+use rate_limit::RateLimiter;
+use render::{body_limit_error, handle_render};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use single_flight::SingleFlight;
+use sources::{
+    Bitbucket, FetchedFile, Gist, GistFile, GitHub, GitLab, Gitea, HttpClient, RawUrl, RepoFile,
+    Revalidated, SourceProvider, Sources,
+};
+use std::collections::HashSet;
+use std::fmt;
+use std::time::SystemTime;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, PoisonError},
+    time::Duration,
+};
+use svg::{generate_badge, generate_svg_with_options, validate_input, BadgeContent, Years};
+use tower_http::{cors::CorsLayer, limit::RequestBodyLimitLayer, trace::TraceLayer};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use webhook::handle_github_webhook;
+
+// Clients may keep their copy, but have to revalidate it with the ETag before every use
+const CACHE_CONTROL: &str = "public, max-age=0, must-revalidate";
+
+/// Branch name standing in for the default branch of a repository
+const DEFAULT_BRANCH: &str = "HEAD";
+
+// Error bodies are short messages, anything bigger is replaced by the status
+const MAX_SHIELDS_ERROR: usize = 4096;
+
+/// What an invalid or missing resource is about, so responses can describe the error without
+/// parsing its message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorKind {
+    /// The query parameters of the request
+    Query,
+    /// The path of the request, or the route itself
+    Path,
+    /// The stars file the request points to
+    File,
+}
+
+#[derive(Debug, Clone)]
+enum AppError {
+    RateLimitExceeded,
+    FetchError(String),
+    ValidationError(ErrorKind, String),
+    FileTooBig { size: u64, max: u64 },
+    NotFound(ErrorKind, String),
+}
+
+impl AppError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            AppError::ValidationError(kind, _) | AppError::NotFound(kind, _) => *kind,
+            // Failing to load the file
+            AppError::RateLimitExceeded | AppError::FetchError(_) | AppError::FileTooBig { .. } => {
+                ErrorKind::File
+            }
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from(&self);
+        let kind = self.kind();
+        let message = match self {
+            AppError::FetchError(msg)
+            | AppError::ValidationError(_, msg)
+            | AppError::NotFound(_, msg) => msg,
+            e => e.to_string(),
+        };
+        error_response(status, kind, message)
+    }
+}
+
+/// A plain text error response, with the kind of error as an extension so `render_error_images`
+/// can tell what it's about
+fn error_response(status: StatusCode, kind: ErrorKind, message: String) -> Response {
+    let mut response = (status, message).into_response();
+    response.extensions_mut().insert(kind);
+    response
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::RateLimitExceeded => write!(f, "Rate limit exceeded"),
+            AppError::FetchError(msg) => write!(f, "Fetch error: {}", msg),
+            AppError::ValidationError(_, msg) => write!(f, "Validation error: {}", msg),
+            AppError::FileTooBig { size, max } => {
+                write!(
+                    f,
+                    "File size {} bytes exceeds maximum allowed size of {} bytes",
+                    size, max
+                )
+            }
+            AppError::NotFound(_, msg) => write!(f, "Not found: {}", msg),
+        }
+    }
+}
+
+impl From<&AppError> for StatusCode {
+    fn from(error: &AppError) -> Self {
+        match error {
+            AppError::RateLimitExceeded => StatusCode::TOO_MANY_REQUESTS,
+            AppError::FetchError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::ValidationError(..) => StatusCode::BAD_REQUEST,
+            AppError::FileTooBig { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::NotFound(..) => StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+type AppResult<T> = Result<T, AppError>;
+
+/// The headers of a request, along with the address of the client making it
+struct RequestContext {
+    client_ip: IpAddr,
+    headers: HeaderMap,
+}
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for RequestContext {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Missing connection info, unable to tell the client IP");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unable to tell the client IP",
+                )
+            })?;
+
+        Ok(Self {
+            client_ip: state.client_ip.resolve(&parts.headers, peer.ip()),
+            headers: parts.headers.clone(),
+        })
+    }
+}
+
+/// The stars file is always a `.txt` file, with the route using the extension of the response
+fn stars_txt_file(file: &str) -> String {
+    let name = [".svg", ".json"]
+        .iter()
+        .find_map(|ext| file.strip_suffix(ext))
+        .unwrap_or(file);
+    format!("{}.txt", name)
+}
+
+fn create_cache_key<P: SourceProvider>(provider: &P, location: &P::Location) -> String {
+    format!("{}:{}", provider.name(), location)
+}
+
+/// Respond with the content, or with a 304 when the `If-None-Match` of the request shows the
+/// client already has it
+fn content_response(
+    cached: CachedContent,
+    content_type: &str,
+    cache_status: &str,
+    headers: &HeaderMap,
+    request_id: Uuid,
+) -> Response {
+    let response_headers = [
+        ("Content-Type", content_type),
+        ("Cache-Control", CACHE_CONTROL),
+        ("ETag", &cached.etag),
+        ("X-Cache", cache_status),
+    ];
+
+    let mut response = if if_none_match(headers, &cached.etag) {
+        (StatusCode::NOT_MODIFIED, response_headers).into_response()
+    } else {
+        (StatusCode::OK, response_headers, cached.content).into_response()
+    };
+    add_response_headers(&mut response, request_id);
+    response
+}
+
+enum CacheLookup {
+    Fresh(CachedContent),
+    /// Expired, but recent enough to be served while it's refreshed
+    Stale(CachedContent),
+    Miss,
+}
+
+async fn check_success_cache(state: &AppState, cache_key: &str) -> CacheLookup {
+    let lookup = match state.cache.get(cache_key).await {
+        Some(cached) if cached.age() < state.cache_ttl => CacheLookup::Fresh(cached),
+        Some(cached) if cached.age() < state.cache_ttl + state.max_stale => {
+            CacheLookup::Stale(cached)
+        }
+        _ => CacheLookup::Miss,
+    };
+
+    let result = match lookup {
+        CacheLookup::Fresh(_) => CacheResult::Hit,
+        CacheLookup::Stale(_) => CacheResult::Stale,
+        CacheLookup::Miss => CacheResult::Miss,
+    };
+    state
+        .metrics
+        .record_cache_lookup(CacheKind::Response, result);
+    lookup
+}
+
+async fn check_error_cache(state: &AppState, cache_key: &str) -> Option<Response> {
+    let cached_error = state.error_cache.get(cache_key);
+    let result = match cached_error {
+        Some(_) => CacheResult::Hit,
+        None => CacheResult::Miss,
+    };
+    state.metrics.record_cache_lookup(CacheKind::Error, result);
+
+    cached_error.map(|cached| error_response(cached.status, cached.kind, cached.message))
+}
+
+fn check_rate_limit(state: &AppState, ip: &str, cache_key: &str) -> AppResult<()> {
+    let ip_key = format!("{}:{}", ip, cache_key);
+
+    if state.rate_limiter.check(&ip_key) {
+        Ok(())
+    } else {
+        state.metrics.record_rate_limited();
+        warn!(
+            client_ip = %ip,
+            cache_key = %cache_key,
+            algorithm = %state.rate_limiter.algorithm,
+            max_requests = state.rate_limiter.max_requests,
+            "Rate limit exceeded"
+        );
+        Err(AppError::RateLimitExceeded)
+    }
+}
+
+/// A rendered response, along with its `ETag`
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedContent {
+    content: String,
+    etag: String,
+    /// Wall clock time, as the entry may outlive the process with the disk cache
+    created: SystemTime,
+}
+
+impl CachedContent {
+    fn new(content: String) -> Self {
+        let etag = content_etag(&content);
+        Self {
+            content,
+            etag,
+            created: SystemTime::now(),
+        }
+    }
+
+    fn age(&self) -> Duration {
+        // A clock going backwards makes the entry fresh again, which is harmless
+        self.created.elapsed().unwrap_or_default()
+    }
+}
+
+/// The keys a response is cached under
+#[derive(Clone)]
+struct CacheKeys {
+    /// Errors fetching or validating are cached under the file
+    file: String,
+    /// The rendered response is cached under the file and the variant, along with errors rendering
+    /// it, like a badge for a year missing from the file
+    content: String,
+}
+
+#[derive(Clone)]
+struct CachedError {
+    status: StatusCode,
+    kind: ErrorKind,
+    message: String,
+}
+
+impl From<&AppError> for CachedError {
+    fn from(error: &AppError) -> Self {
+        Self {
+            status: StatusCode::from(error),
+            kind: error.kind(),
+            message: error.to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    cache: Arc<dyn CacheStore<CachedContent>>,
+    /// How long cached responses are fresh for
+    cache_ttl: Duration,
+    /// How long after expiring cached responses are still served while being refreshed
+    max_stale: Duration,
+    /// Cache keys of the stale responses currently being refreshed in the background
+    refreshing: Arc<std::sync::Mutex<HashSet<String>>>,
+    /// Fetches in progress, keyed by the file, so concurrent cache misses share one fetch
+    fetches: Arc<SingleFlight<AppResult<Years>>>,
+    /// Default branch lookups in progress, keyed like `default_branches`
+    branch_fetches: Arc<SingleFlight<AppResult<String>>>,
+    error_cache: Arc<Cache<String, CachedError>>,
+    /// Fetched files with their upstream `ETag`, kept longer than the rendered responses so
+    /// expired responses can be revalidated instead of downloaded again
+    file_cache: Arc<dyn CacheStore<FetchedFile>>,
+    /// Default branches of GitHub repositories, by `github:user/repo`
+    default_branches: Arc<Cache<String, String>>,
+    client: HttpClient,
+    rate_limiter: Arc<RateLimiter>,
+    /// Limits `POST /render` by client, separately from the fetches
+    render_rate_limiter: Arc<RateLimiter>,
+    render_max_body_size: usize,
+    client_ip: Arc<ClientIpResolver>,
+    metrics: Arc<Metrics>,
+    /// Secret for verifying GitHub webhooks, which are disabled without one
+    github_webhook_secret: Option<Arc<str>>,
+    /// Token for the admin routes, which are disabled without one
+    admin_token: Option<Arc<str>>,
+    sources: Arc<Sources>,
+}
+
+impl AppState {
+    fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let cache_ttl = Duration::from_secs(config.cache_ttl_secs);
+        let max_stale = Duration::from_secs(config.max_stale_secs);
+
+        // Responses are kept around until they are too stale to be served
+        let cache = build_store(
+            config.cache_backend,
+            &config.cache_dir.join("responses"),
+            cache_ttl + max_stale,
+            config.max_cache_size,
+        )?;
+
+        let error_cache: Cache<String, CachedError> = Cache::builder()
+            .time_to_live(Duration::from_secs(config.error_cache_ttl_secs))
+            .time_to_idle(Duration::from_secs(config.error_cache_ttl_secs * 2))
+            .max_capacity(config.max_cache_size)
+            .build();
+
+        let file_cache = build_store(
+            config.cache_backend,
+            &config.cache_dir.join("files"),
+            Duration::from_secs(config.file_cache_ttl_secs),
+            config.max_cache_size,
+        )?;
+
+        let default_branches = Cache::builder()
+            .time_to_live(Duration::from_secs(config.default_branch_ttl_secs))
+            .max_capacity(config.max_cache_size)
+            .build();
+
+        let rate_limiter = RateLimiter::new(
+            config.rate_limit_algorithm,
+            Duration::from_secs(config.rate_limit_window_secs),
+            config.rate_limit_max_requests,
+            config.rate_limit_max_entries,
+        );
+        let render_rate_limiter = RateLimiter::new(
+            config.rate_limit_algorithm,
+            Duration::from_secs(config.rate_limit_window_secs),
+            config.render_rate_limit_max_requests,
+            config.rate_limit_max_entries,
+        );
+
+        let client = HttpClient::new(
+            Duration::from_secs(config.http_timeout_secs),
+            &config.user_agent,
+            config.max_file_size,
+        )?;
+
+        let metrics = Arc::new(Metrics::new());
+        let github = GitHub::new(&config.github_api_url, config.github_token.clone())
+            .with_owner_tokens(&config.github_owner_tokens)
+            .with_metrics(metrics.clone());
+        let sources = Sources {
+            gist: Gist::new(github.clone()),
+            github,
+            gitlab: GitLab::new(&config.gitlab_url),
+            gitea: Gitea::new(&config.gitea_url),
+            bitbucket: Bitbucket::new(&config.bitbucket_api_url),
+            raw: RawUrl::new(config.raw_url_allowlist.clone()),
+        };
+
+        Ok(Self {
+            cache,
+            cache_ttl,
+            max_stale,
+            refreshing: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            fetches: Arc::new(SingleFlight::new()),
+            branch_fetches: Arc::new(SingleFlight::new()),
+            error_cache: Arc::new(error_cache),
+            file_cache,
+            default_branches: Arc::new(default_branches),
+            client,
+            rate_limiter: Arc::new(rate_limiter),
+            render_rate_limiter: Arc::new(render_rate_limiter),
+            render_max_body_size: config.render_max_body_size,
+            client_ip: Arc::new(ClientIpResolver::new(
+                config.client_ip_header,
+                config.trusted_proxies.clone(),
+            )),
+            metrics,
+            github_webhook_secret: (!config.github_webhook_secret.is_empty())
+                .then(|| config.github_webhook_secret.as_str().into()),
+            admin_token: (!config.admin_token.is_empty())
+                .then(|| config.admin_token.as_str().into()),
+            sources: Arc::new(sources),
+        })
+    }
+}
+
+impl FromRef<AppState> for Arc<Metrics> {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+fn router(state: AppState) -> Router {
+    // Create CORS layer
+    // `POST /render` is called from browser tools as well, with a JSON body needing a preflight
+    let cors = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([axum::http::header::CONTENT_TYPE])
+        .allow_origin("*".parse::<HeaderValue>().unwrap());
+
+    Router::new()
+        .route("/health", get(health))
+        .route("/stars/:user/:repo/:branch/*file.svg", get(handle_stars))
+        .route("/badge/:user/:repo/:branch/*file.svg", get(handle_badge))
+        .route(
+            "/shields/:user/:repo/:branch/*file.json",
+            get(handle_shields),
+        )
+        .route("/data/:user/:repo/:branch/*file.json", get(handle_data))
+        .route("/gist/:user/:gist_id/*file.svg", get(handle_gist_stars))
+        .route(
+            "/gitlab/:user/:repo/:branch/*file.svg",
+            get(handle_gitlab_stars),
+        )
+        .route(
+            "/gitea/:user/:repo/:branch/*file.svg",
+            get(handle_gitea_stars),
+        )
+        .route(
+            "/bitbucket/:user/:repo/:branch/*file.svg",
+            get(handle_bitbucket_stars),
+        )
+        .route("/raw", get(handle_raw_stars))
+        .route(
+            "/render",
+            post(handle_render)
+                .layer(RequestBodyLimitLayer::new(state.render_max_body_size))
+                .layer(middleware::map_response(body_limit_error)),
+        )
+        .route("/metrics", get(handle_metrics))
+        .route("/webhooks/github", post(handle_github_webhook))
+        .nest("/admin", admin_router(state.clone()))
+        .route_layer(middleware::from_fn_with_state(
+            state.metrics.clone(),
+            track_requests,
+        ))
+        .with_state(state)
+        .layer(
+            tower::ServiceBuilder::new()
+                .layer(TraceLayer::new_for_http())
+                .layer(cors)
+                .layer(middleware::from_fn(render_error_images)),
+        )
+}
+
+/// Build the router of the API from the config, logging which optional features are enabled.
+///
+/// Requests are rate limited by client IP, so the router has to be served with
+/// `into_make_service_with_connect_info::<SocketAddr>()`.
+pub fn build_router(config: &Config) -> Result<Router, Box<dyn std::error::Error>> {
+    let state = AppState::new(config)?;
+
+    if state.sources.github.has_token() {
+        info!("GitHub API authentication enabled");
+    } else {
+        warn!("Running without GitHub token, rate limits will apply");
+    }
+    let owner_tokens = state.sources.github.owner_token_count();
+    if owner_tokens > 0 {
+        info!(
+            owners = owner_tokens,
+            "GitHub tokens enabled for private repositories"
+        );
+    }
+
+    if state.sources.raw.is_enabled() {
+        info!("Raw URL source enabled");
+    }
+
+    if state.github_webhook_secret.is_some() {
+        info!("GitHub webhooks enabled");
+    }
+
+    if state.admin_token.is_some() {
+        info!("Admin routes enabled");
+    }
+
+    if state.max_stale.is_zero() {
+        info!("Serving stale responses disabled");
+    }
+
+    if config.cache_backend == CacheBackend::Disk {
+        info!(dir = %config.cache_dir.display(), "Caching on disk");
+    }
+
+    Ok(router(state))
+}
+
+async fn health() -> Response {
+    StatusCode::OK.into_response()
+}
+
+/// Remove the entries with matching keys, returning how many were removed
+async fn invalidate_matching<V>(
+    cache: &(impl CacheStore<V> + ?Sized),
+    matches: impl Fn(&str) -> bool,
+) -> usize {
+    let keys: Vec<_> = cache
+        .entries()
+        .await
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| matches(key))
+        .collect();
+    for key in &keys {
+        cache.invalidate(key).await;
+    }
+    keys.len()
+}
+
+fn add_response_headers(response: &mut Response, request_id: Uuid) {
+    let headers = response.headers_mut();
+    headers.insert(
+        "X-Request-ID",
+        HeaderValue::from_str(&request_id.to_string())
+            .unwrap_or_else(|_| HeaderValue::from_static("")),
+    );
+}
+
+/// The parts of a stars route path, identifying a file in a repository
+type StarsPath = (String, String, String, String);
+
+fn repo_file((user, repo, branch, file): StarsPath) -> AppResult<RepoFile> {
+    RepoFile::new(user, repo, branch, stars_txt_file(&file))
+}
+
+/// The file in a GitHub repository, with the `HEAD` branch resolved to the default branch
+async fn github_file(
+    state: &AppState,
+    request: &RequestContext,
+    path: StarsPath,
+) -> Result<RepoFile, Response> {
+    let file = repo_file(path).map_err(IntoResponse::into_response)?;
+    resolve_default_branch(state, request, file).await
+}
+
+#[derive(Deserialize)]
+struct YearParams {
+    year: Option<usize>,
+}
+
+/// Axum rejects query strings that don't deserialize with its own plain text response, so turn
+/// that into an error about the query
+fn year_params(params: Result<Query<YearParams>, QueryRejection>) -> AppResult<YearParams> {
+    params
+        .map(|Query(params)| params)
+        .map_err(|rejection| AppError::ValidationError(ErrorKind::Query, rejection.body_text()))
+}
+
+async fn handle_stars(
+    Path(path): Path<StarsPath>,
+    Query(params): Query<Vec<(String, String)>>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request: RequestContext,
+) -> Response {
+    let file = match github_file(&state, &request, path).await {
+        Ok(file) => file,
+        Err(response) => return response,
+    };
+    let github = &state.sources.github;
+    serve_stars_svg(&state, github, file, &request, &params).await
+}
+
+async fn handle_gist_stars(
+    Path((user, gist_id, file)): Path<(String, String, String)>,
+    Query(params): Query<Vec<(String, String)>>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request: RequestContext,
+) -> Response {
+    let gist = &state.sources.gist;
+    let location = match GistFile::new(user, gist_id, stars_txt_file(&file)) {
+        Ok(location) => location,
+        Err(e) => return e.into_response(),
+    };
+    serve_stars_svg(&state, gist, location, &request, &params).await
+}
+
+async fn handle_gitlab_stars(
+    Path(path): Path<StarsPath>,
+    Query(params): Query<Vec<(String, String)>>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request: RequestContext,
+) -> Response {
+    let file = match repo_file(path) {
+        Ok(file) => file,
+        Err(e) => return e.into_response(),
+    };
+    let gitlab = &state.sources.gitlab;
+    serve_stars_svg(&state, gitlab, file, &request, &params).await
+}
+
+async fn handle_gitea_stars(
+    Path(path): Path<StarsPath>,
+    Query(params): Query<Vec<(String, String)>>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request: RequestContext,
+) -> Response {
+    let file = match repo_file(path) {
+        Ok(file) => file,
+        Err(e) => return e.into_response(),
+    };
+    let gitea = &state.sources.gitea;
+    serve_stars_svg(&state, gitea, file, &request, &params).await
+}
+
+async fn handle_bitbucket_stars(
+    Path(path): Path<StarsPath>,
+    Query(params): Query<Vec<(String, String)>>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request: RequestContext,
+) -> Response {
+    let file = match repo_file(path) {
+        Ok(file) => file,
+        Err(e) => return e.into_response(),
+    };
+    let bitbucket = &state.sources.bitbucket;
+    serve_stars_svg(&state, bitbucket, file, &request, &params).await
+}
+
+async fn handle_raw_stars(
+    Query(mut params): Query<Vec<(String, String)>>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request: RequestContext,
+) -> Response {
+    let raw = &state.sources.raw;
+    if !raw.is_enabled() {
+        return AppError::NotFound(ErrorKind::Path, "Raw URL source is not enabled".to_string())
+            .into_response();
+    }
+
+    let Some(index) = params.iter().position(|(key, _)| key == "url") else {
+        return AppError::ValidationError(
+            ErrorKind::Query,
+            "Missing query parameter `url`".to_string(),
+        )
+        .into_response();
+    };
+    let (_, url) = params.remove(index);
+
+    let url = match raw.parse_url(&url) {
+        Ok(url) => url,
+        Err(e) => return e.into_response(),
+    };
+
+    serve_stars_svg(&state, raw, url, &request, &params).await
+}
+
+/// Resolve the `HEAD` branch of a GitHub repository to its default branch, so the responses are
+/// cached under the actual branch and shared with requests naming it. Files on other branches are
+/// returned as they are.
+async fn resolve_default_branch(
+    state: &AppState,
+    request: &RequestContext,
+    mut file: RepoFile,
+) -> Result<RepoFile, Response> {
+    if file.branch != DEFAULT_BRANCH {
+        return Ok(file);
+    }
+
+    let github = &state.sources.github;
+    let cache_key = format!("{}:{}/{}", github.name(), file.user, file.repo);
+    let cached = state.default_branches.get(&cache_key);
+    let result = match cached {
+        Some(_) => CacheResult::Hit,
+        None => CacheResult::Miss,
+    };
+    state
+        .metrics
+        .record_cache_lookup(CacheKind::DefaultBranch, result);
+    if let Some(branch) = cached {
+        file.branch = branch;
+        return Ok(file);
+    }
+
+    if let Some(response) = check_error_cache(state, &cache_key).await {
+        return Err(response);
+    }
+    let client_ip = request.client_ip.to_string();
+    check_rate_limit(state, &client_ip, &cache_key).map_err(IntoResponse::into_response)?;
+
+    // Concurrent requests for the same repository share the lookup
+    let lookup = state.branch_fetches.run(&cache_key, || async {
+        match github
+            .fetch_default_branch(&state.client, &file.user, &file.repo)
+            .await
+        {
+            Ok(branch) => {
+                info!(
+                    client_ip = %client_ip,
+                    cache_key = %cache_key,
+                    branch = %branch,
+                    "Resolved default branch"
+                );
+                state
+                    .default_branches
+                    .insert(cache_key.clone(), branch.clone());
+                Ok(branch)
+            }
+            Err(e) => {
+                warn!(
+                    client_ip = %client_ip,
+                    cache_key = %cache_key,
+                    error = %e,
+                    "Failed to resolve default branch"
+                );
+                state
+                    .error_cache
+                    .insert(cache_key.clone(), CachedError::from(&e));
+                Err(e)
+            }
+        }
+    });
+
+    match lookup.await {
+        Ok(branch) => {
+            file.branch = branch;
+            Ok(file)
+        }
+        Err(e) => Err(e.into_response()),
+    }
+}
+
+/// Serve the stars grid, with the theme and layout taken from the query parameters
+async fn serve_stars_svg<P: SourceProvider>(
+    state: &AppState,
+    provider: &P,
+    location: P::Location,
+    request: &RequestContext,
+    params: &[(String, String)],
+) -> Response {
+    let options = match parse_render_options(params) {
+        Ok(options) => options,
+        Err(e) => return e.into_response(),
+    };
+    let variant = render_variant(&options);
+
+    serve_stars(
+        state,
+        provider,
+        location,
+        request,
+        variant.as_deref(),
+        "image/svg+xml",
+        move |years| Ok(generate_svg_with_options(years, &options)),
+    )
+    .await
+}
+
+async fn handle_badge(
+    Path(path): Path<StarsPath>,
+    params: Result<Query<YearParams>, QueryRejection>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request: RequestContext,
+) -> Response {
+    let params = match year_params(params) {
+        Ok(params) => params,
+        Err(e) => return e.into_response(),
+    };
+    let file = match github_file(&state, &request, path).await {
+        Ok(file) => file,
+        Err(response) => return response,
+    };
+    let variant = match params.year {
+        Some(year) => format!("badge:{}", year),
+        None => "badge".to_string(),
+    };
+
+    serve_stars(
+        &state,
+        &state.sources.github,
+        file,
+        &request,
+        Some(&variant),
+        "image/svg+xml",
+        move |years| {
+            generate_badge(&years, params.year)
+                .map_err(|e| AppError::NotFound(ErrorKind::Query, e.to_string()))
+        },
+    )
+    .await
+}
+
+async fn handle_shields(
+    Path(path): Path<StarsPath>,
+    params: Result<Query<YearParams>, QueryRejection>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request: RequestContext,
+) -> Response {
+    let params = year_params(params);
+    let year = params.as_ref().ok().and_then(|params| params.year);
+    let label = year.map_or_else(|| "AoC".to_string(), |year| year.to_string());
+
+    let response = match params {
+        Ok(_) => serve_shields(&state, &request, path, year).await,
+        Err(e) => e.into_response(),
+    };
+    shields_error(response, &label).await
+}
+
+async fn serve_shields(
+    state: &AppState,
+    request: &RequestContext,
+    path: StarsPath,
+    year: Option<usize>,
+) -> Response {
+    let file = match github_file(state, request, path).await {
+        Ok(file) => file,
+        Err(response) => return response,
+    };
+    let variant = match year {
+        Some(year) => format!("shields:{}", year),
+        None => "shields".to_string(),
+    };
+
+    serve_stars(
+        state,
+        &state.sources.github,
+        file,
+        request,
+        Some(&variant),
+        "application/json",
+        move |years| {
+            let content = BadgeContent::new(&years, year)
+                .map_err(|e| AppError::NotFound(ErrorKind::Query, e.to_string()))?;
+            // The JSON schema of a shields.io endpoint badge, see
+            // https://shields.io/badges/endpoint-badge
+            let endpoint = serde_json::json!({
+                "schemaVersion": 1,
+                "label": content.label,
+                "message": content.message,
+                "color": content.colour.trim_start_matches('#'),
+            });
+            Ok(endpoint.to_string())
+        },
+    )
+    .await
+}
+
+/// Turn a plain text error into an endpoint badge with `isError`, which shields.io shows as an
+/// error badge. shields.io only reads the body of successful responses, so the error is sent as
+/// a 200.
+async fn shields_error(response: Response, label: &str) -> Response {
+    let status = response.status();
+    if !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let message = match axum::body::to_bytes(body, MAX_SHIELDS_ERROR).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => status.to_string(),
+    };
+    let endpoint = serde_json::json!({
+        "schemaVersion": 1,
+        "label": label,
+        "message": message,
+        "isError": true,
+    });
+
+    parts.status = StatusCode::OK;
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+    parts.headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    parts.headers.insert(
+        axum::http::header::CACHE_CONTROL,
+        HeaderValue::from_static("no-cache"),
+    );
+    Response::from_parts(parts, axum::body::Body::from(endpoint.to_string()))
+}
+
+async fn handle_data(
+    Path(path): Path<StarsPath>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request: RequestContext,
+) -> Response {
+    let file = match github_file(&state, &request, path).await {
+        Ok(file) => file,
+        Err(response) => return response,
+    };
+    serve_stars(
+        &state,
+        &state.sources.github,
+        file,
+        &request,
+        Some("data"),
+        "application/json",
+        |years| {
+            serde_json::to_string(&StarsData::new(years))
+                .map_err(|e| AppError::FetchError(format!("Failed to serialize data: {}", e)))
+        },
+    )
+    .await
+}
+
+/// Fetch, validate and render a stars file, going through the caches and rate limiting.
+///
+/// Errors fetching or validating the file are cached under the file itself, while the rendered
+/// output is cached under the `variant` as well, so different renderings of the same file don't
+/// collide. Expired responses are served as stale while they are refreshed in the background.
+async fn serve_stars<P, F>(
+    state: &AppState,
+    provider: &P,
+    location: P::Location,
+    request: &RequestContext,
+    variant: Option<&str>,
+    content_type: &'static str,
+    render: F,
+) -> Response
+where
+    P: SourceProvider,
+    F: FnOnce(Years) -> AppResult<String> + Send + 'static,
+{
+    let request_id = Uuid::new_v4();
+    let file_key = create_cache_key(provider, &location);
+    let keys = CacheKeys {
+        content: match variant {
+            Some(variant) => format!("{}#{}", file_key, variant),
+            None => file_key.clone(),
+        },
+        file: file_key,
+    };
+    let client_ip = request.client_ip.to_string();
+    let headers = &request.headers;
+
+    match check_success_cache(state, &keys.content).await {
+        CacheLookup::Fresh(cached) => {
+            info!(
+                client_ip = %client_ip,
+                request_id = %request_id,
+                cache_key = %keys.content,
+                "Cache hit"
+            );
+            return content_response(cached, content_type, "HIT", headers, request_id);
+        }
+        CacheLookup::Stale(cached) => {
+            info!(
+                client_ip = %client_ip,
+                request_id = %request_id,
+                cache_key = %keys.content,
+                age_secs = cached.age().as_secs(),
+                "Serving stale cache entry"
+            );
+            spawn_refresh(
+                state, provider, location, keys, client_ip, request_id, render,
+            )
+            .await;
+            return content_response(cached, content_type, "STALE", headers, request_id);
+        }
+        CacheLookup::Miss => {}
+    }
+
+    for cache_key in [&keys.file, &keys.content] {
+        if let Some(response) = check_error_cache(state, cache_key).await {
+            info!(
+                client_ip = %client_ip,
+                request_id = %request_id,
+                cache_key = %cache_key,
+                "Error cache hit"
+            );
+            return response;
+        }
+    }
+
+    // We only care about checking rate limits after the cache, as the rate limit is to prevent
+    // excessive upstream requests
+    if let Err(e) = check_rate_limit(state, &client_ip, &keys.file) {
+        warn!(
+            client_ip = %client_ip,
+            request_id = %request_id,
+            cache_key = %keys.file,
+            "Rate limit exceeded"
+        );
+        return e.into_response();
+    }
+
+    match render_stars(
+        state, provider, &location, &keys, &client_ip, request_id, render,
+    )
+    .await
+    {
+        Ok(cached) => content_response(cached, content_type, "MISS", headers, request_id),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Refresh a stale response in the background. The stale copy stays in the cache until the
+/// refresh succeeds, so failures keep serving it.
+async fn spawn_refresh<P, F>(
+    state: &AppState,
+    provider: &P,
+    location: P::Location,
+    keys: CacheKeys,
+    client_ip: String,
+    request_id: Uuid,
+    render: F,
+) where
+    P: SourceProvider,
+    F: FnOnce(Years) -> AppResult<String> + Send + 'static,
+{
+    // A recent error means the refresh would fail again, so don't retry until it expires
+    if state.error_cache.contains_key(&keys.file) || state.error_cache.contains_key(&keys.content) {
+        return;
+    }
+    // Deduplicate first, so requests for a response already being refreshed don't count against
+    // the rate limit
+    let Some(guard) = RefreshGuard::new(&state.refreshing, &keys.content) else {
+        // Another request is already refreshing the response
+        return;
+    };
+    if check_rate_limit(state, &client_ip, &keys.file).is_err() {
+        return;
+    }
+
+    let state = state.clone();
+    let provider = provider.clone();
+    tokio::spawn(async move {
+        // Dropped when the task ends, even when the refresh panics
+        let _guard = guard;
+        let result = render_stars(
+            &state, &provider, &location, &keys, &client_ip, request_id, render,
+        )
+        .await;
+        if let Err(e) = result {
+            warn!(
+                request_id = %request_id,
+                cache_key = %keys.content,
+                error = %e,
+                "Refresh failed, keeping the stale cache entry"
+            );
+        }
+    });
+}
+
+/// Marks a response as being refreshed, until the guard is dropped
+struct RefreshGuard {
+    refreshing: Arc<std::sync::Mutex<HashSet<String>>>,
+    cache_key: String,
+}
+
+impl RefreshGuard {
+    /// Returns `None` when the response is already being refreshed
+    fn new(refreshing: &Arc<std::sync::Mutex<HashSet<String>>>, cache_key: &str) -> Option<Self> {
+        let inserted = refreshing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(cache_key.to_string());
+        inserted.then(|| Self {
+            refreshing: refreshing.clone(),
+            cache_key: cache_key.to_string(),
+        })
+    }
+}
+
+impl Drop for RefreshGuard {
+    fn drop(&mut self) {
+        self.refreshing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.cache_key);
+    }
+}
+
+/// Fetch and render a stars file, caching the rendered response
+async fn render_stars<P, F>(
+    state: &AppState,
+    provider: &P,
+    location: &P::Location,
+    keys: &CacheKeys,
+    client_ip: &str,
+    request_id: Uuid,
+    render: F,
+) -> AppResult<CachedContent>
+where
+    P: SourceProvider,
+    F: FnOnce(Years) -> AppResult<String>,
+{
+    let validated_data = state
+        .fetches
+        .run(&keys.file, || {
+            fetch_stars(state, provider, location, &keys.file, client_ip, request_id)
+        })
+        .await?;
+
+    // The file is valid, so errors only concern this variant and are cached under it
+    let content = render(validated_data).inspect_err(|e| {
+        state
+            .error_cache
+            .insert(keys.content.clone(), CachedError::from(e));
+        warn!(
+            client_ip = %client_ip,
+            request_id = %request_id,
+            cache_key = %keys.content,
+            error = %e,
+            "Render failed"
+        );
+    })?;
+    let cached = CachedContent::new(content);
+    state
+        .cache
+        .insert(keys.content.clone(), cached.clone())
+        .await;
+
+    info!(
+        client_ip = %client_ip,
+        request_id = %request_id,
+        cache_key = %keys.content,
+        "Successfully generated response"
+    );
+
+    Ok(cached)
+}
+
+/// Fetch a stars file from the provider and validate it, caching any errors under `cache_key`.
+///
+/// Files fetched before are revalidated with their upstream `ETag`, reusing the content when the
+/// upstream reports it unchanged.
+async fn fetch_stars<P: SourceProvider>(
+    state: &AppState,
+    provider: &P,
+    location: &P::Location,
+    cache_key: &str,
+    client_ip: &str,
+    request_id: Uuid,
+) -> AppResult<Years> {
+    let previous = state.file_cache.get(cache_key).await;
+    let etag = previous.as_ref().and_then(|file| file.etag.as_deref());
+
+    let content = match provider
+        .fetch_file_if_changed(&state.client, location, etag)
+        .await
+    {
+        Ok(Revalidated::Unchanged) => {
+            info!(
+                request_id = %request_id,
+                cache_key = %cache_key,
+                "Upstream file unchanged"
+            );
+            // Only files with an ETag are revalidated, so the previous file is always there
+            previous.map(|file| file.content).unwrap_or_default()
+        }
+        Ok(Revalidated::Changed(file)) => {
+            if file.etag.is_some() {
+                info!(
+                    request_id = %request_id,
+                    cache_key = %cache_key,
+                    sha = file.sha.as_deref().unwrap_or("unknown"),
+                    "Fetched file"
+                );
+                state
+                    .file_cache
+                    .insert(cache_key.to_string(), file.clone())
+                    .await;
+            }
+            file.content
+        }
+        Err(e) => {
+            error!(
+                client_ip = %client_ip,
+                request_id = %request_id,
+                cache_key = %cache_key,
+                provider = provider.name(),
+                error = %e,
+                "Fetch failed"
+            );
+            state
+                .error_cache
+                .insert(cache_key.to_string(), CachedError::from(&e));
+            return Err(e);
+        }
+    };
+
+    // Validate the content
+    match validate_input(&content) {
+        Ok(data) => Ok(data),
+        Err(e) => {
+            let error = AppError::ValidationError(ErrorKind::File, e.to_string());
+            state.error_cache.insert(
+                cache_key.to_string(),
+                CachedError {
+                    status: StatusCode::BAD_REQUEST,
+                    kind: ErrorKind::File,
+                    message: e.to_string(),
+                },
+            );
+            warn!(
+                client_ip = %client_ip,
+                request_id = %request_id,
+                cache_key = %cache_key,
+                error = %e,
+                "Validation error"
+            );
+            Err(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::extract::connect_info::MockConnectInfo;
+    use axum::extract::Request;
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use client_ip::parse_trusted_proxy;
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    const STARS: &str = "2024: 2,2,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0";
+    const STARS_PATH: &str = "/stars/user/repo/main/stars.svg";
+
+    fn test_config(github_api_url: &str) -> Config {
+        Config {
+            port: 0,
+            http_timeout_secs: 5,
+            max_cache_size: 100,
+            rate_limit_max_entries: 1000,
+            github_api_url: github_api_url.to_string(),
+            gitlab_url: String::new(),
+            gitea_url: String::new(),
+            bitbucket_api_url: String::new(),
+            ..Config::default()
+        }
+    }
+
+    /// A stub GitHub serving the stars file and its repository, counting the requests it gets and
+    /// failing them all once `failing` is set. Responses are delayed by `delay_ms`.
+    struct StubGitHub {
+        base_url: String,
+        requests: Arc<AtomicUsize>,
+        failing: Arc<AtomicBool>,
+        delay_ms: Arc<AtomicU64>,
+    }
+
+    impl StubGitHub {
+        async fn start() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(AtomicUsize::new(0));
+            let failing = Arc::new(AtomicBool::new(false));
+            let delay_ms = Arc::new(AtomicU64::new(0));
+
+            let (counter, fail, delay) = (requests.clone(), failing.clone(), delay_ms.clone());
+            let (repo_counter, repo_fail, repo_delay) =
+                (requests.clone(), failing.clone(), delay_ms.clone());
+            let app = Router::new()
+                .route(
+                    "/repos/user/repo",
+                    get(move || {
+                        repo_counter.fetch_add(1, Ordering::SeqCst);
+                        let fail = repo_fail.load(Ordering::SeqCst);
+                        let delay = Duration::from_millis(repo_delay.load(Ordering::SeqCst));
+                        async move {
+                            tokio::time::sleep(delay).await;
+                            if fail {
+                                return StatusCode::BAD_GATEWAY.into_response();
+                            }
+                            r#"{"default_branch": "main"}"#.into_response()
+                        }
+                    }),
+                )
+                .route(
+                    "/repos/user/repo/contents/stars.txt",
+                    get(move || {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        let fail = fail.load(Ordering::SeqCst);
+                        let delay = Duration::from_millis(delay.load(Ordering::SeqCst));
+                        async move {
+                            tokio::time::sleep(delay).await;
+                            if fail {
+                                return StatusCode::BAD_GATEWAY.into_response();
+                            }
+                            format!(
+                                r#"{{"size": {}, "encoding": "base64", "content": "{}"}}"#,
+                                STARS.len(),
+                                BASE64_STANDARD.encode(STARS)
+                            )
+                            .into_response()
+                        }
+                    }),
+                );
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+            Self {
+                base_url,
+                requests,
+                failing,
+                delay_ms,
+            }
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+    }
+
+    /// The app, with requests coming from a local peer
+    fn test_router(state: AppState) -> Router {
+        router(state).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))))
+    }
+
+    async fn get_stars(app: &Router) -> (StatusCode, String, String) {
+        send(app, Request::get(STARS_PATH).body(Body::empty()).unwrap()).await
+    }
+
+    /// Send the request, returning the status, `X-Cache` header and body of the response
+    async fn send(app: &Router, request: Request) -> (StatusCode, String, String) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let x_cache = response
+            .headers()
+            .get("X-Cache")
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, x_cache, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn get_stars_concurrently(
+        app: &Router,
+        count: usize,
+    ) -> Vec<(StatusCode, String, String)> {
+        let requests: Vec<_> = (0..count)
+            .map(|_| {
+                let app = app.clone();
+                tokio::spawn(async move { get_stars(&app).await })
+            })
+            .collect();
+
+        let mut responses = Vec::new();
+        for request in requests {
+            responses.push(request.await.unwrap());
+        }
+        responses
+    }
+
+    /// Wait for the background refreshes to finish
+    async fn wait_for_refreshes(state: &AppState) {
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if state.refreshing.lock().unwrap().is_empty() {
+                return;
+            }
+        }
+        panic!("Refresh didn't finish");
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate() {
+        let github = StubGitHub::start().await;
+        let config = Config {
+            // Responses are stale straight away
+            cache_ttl_secs: 0,
+            ..test_config(&github.base_url)
+        };
+        let state = AppState::new(&config).unwrap();
+        let app = test_router(state.clone());
+
+        let (status, x_cache, original) = get_stars(&app).await;
+        assert_eq!((status, x_cache.as_str()), (StatusCode::OK, "MISS"));
+        assert_eq!(github.requests(), 1);
+
+        // The stale response is served straight away, while it's refreshed in the background
+        let (status, x_cache, body) = get_stars(&app).await;
+        assert_eq!((status, x_cache.as_str()), (StatusCode::OK, "STALE"));
+        assert_eq!(body, original);
+        wait_for_refreshes(&state).await;
+        assert_eq!(github.requests(), 2);
+
+        // Failing refreshes keep the stale response
+        github.failing.store(true, Ordering::SeqCst);
+        let (status, x_cache, _) = get_stars(&app).await;
+        assert_eq!((status, x_cache.as_str()), (StatusCode::OK, "STALE"));
+        wait_for_refreshes(&state).await;
+        assert_eq!(github.requests(), 3);
+
+        // The failure is cached, so it's not retried straight away
+        let (status, x_cache, body) = get_stars(&app).await;
+        assert_eq!((status, x_cache.as_str()), (StatusCode::OK, "STALE"));
+        assert_eq!(body, original);
+        wait_for_refreshes(&state).await;
+        assert_eq!(github.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_guard() {
+        let github = StubGitHub::start().await;
+        let config = Config {
+            rate_limit_max_requests: 2,
+            ..test_config(&github.base_url)
+        };
+        let state = AppState::new(&config).unwrap();
+        let keys = CacheKeys {
+            file: "github:user/repo/main/stars.txt".to_string(),
+            content: "github:user/repo/main/stars.txt".to_string(),
+        };
+        let refresh = |render: fn(Years) -> AppResult<String>| {
+            let file = RepoFile::new(
+                "user".to_string(),
+                "repo".to_string(),
+                "main".to_string(),
+                "stars.txt".to_string(),
+            )
+            .unwrap();
+            spawn_refresh(
+                &state,
+                &state.sources.github,
+                file,
+                keys.clone(),
+                "127.0.0.1".to_string(),
+                Uuid::new_v4(),
+                render,
+            )
+        };
+
+        // A refresh that panics is still cleared, so the response can be refreshed again
+        github.delay_ms.store(100, Ordering::SeqCst);
+        refresh(|_| panic!("render failed")).await;
+        // The duplicate doesn't count against the rate limit
+        refresh(|_| Ok(String::new())).await;
+        assert!(state.refreshing.lock().unwrap().contains(&keys.content));
+        wait_for_refreshes(&state).await;
+        assert_eq!(github.requests(), 1);
+        assert!(state
+            .rate_limiter
+            .check(&format!("127.0.0.1:{}", keys.file)));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_misses_share_a_fetch() {
+        let github = StubGitHub::start().await;
+        github.delay_ms.store(200, Ordering::SeqCst);
+        let app = test_router(AppState::new(&test_config(&github.base_url)).unwrap());
+
+        let responses = get_stars_concurrently(&app, 5).await;
+        for (status, x_cache, _) in &responses {
+            assert_eq!((*status, x_cache.as_str()), (StatusCode::OK, "MISS"));
+        }
+        assert_eq!(github.requests(), 1);
+
+        // Errors are shared as well
+        let app = test_router(AppState::new(&test_config(&github.base_url)).unwrap());
+        github.failing.store(true, Ordering::SeqCst);
+        let responses = get_stars_concurrently(&app, 5).await;
+        for (status, _, _) in &responses {
+            assert_eq!(*status, StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        assert_eq!(github.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_by_client_ip() {
+        let github = StubGitHub::start().await;
+        // Each request uses a different variant, so it misses the cache and is rate limited
+        let get_forwarded_for = |app: Router, cell: usize, forwarded_for: &'static str| async move {
+            let request = Request::get(format!("{}?cell={}", STARS_PATH, cell))
+                .header("X-Forwarded-For", forwarded_for)
+                .body(Body::empty())
+                .unwrap();
+            send(&app, request).await.0
+        };
+
+        let config = Config {
+            rate_limit_max_requests: 1,
+            ..test_config(&github.base_url)
+        };
+        let app = test_router(AppState::new(&config).unwrap());
+        assert_eq!(
+            get_forwarded_for(app.clone(), 20, "198.51.100.1").await,
+            StatusCode::OK
+        );
+        // The peer isn't a trusted proxy, so a spoofed header doesn't get around the limit
+        assert_eq!(
+            get_forwarded_for(app, 24, "198.51.100.2").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        let config = Config {
+            trusted_proxies: vec![parse_trusted_proxy("127.0.0.0/8").unwrap()],
+            ..config
+        };
+        let app = test_router(AppState::new(&config).unwrap());
+        assert_eq!(
+            get_forwarded_for(app.clone(), 20, "198.51.100.1").await,
+            StatusCode::OK
+        );
+        // Behind a trusted proxy, each client has its own limit
+        assert_eq!(
+            get_forwarded_for(app.clone(), 24, "198.51.100.2").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get_forwarded_for(app, 28, "198.51.100.2").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_webhook_invalidates_cache() {
+        use hmac::{Hmac, Mac};
+
+        let github = StubGitHub::start().await;
+        let config = Config {
+            github_webhook_secret: "secret".to_string(),
+            ..test_config(&github.base_url)
+        };
+        let app = test_router(AppState::new(&config).unwrap());
+        let push = |files: &[&str]| {
+            let body = serde_json::json!({
+                "ref": "refs/heads/main",
+                "repository": {"full_name": "user/repo"},
+                "commits": [{"added": [], "removed": [], "modified": files}],
+            })
+            .to_string();
+            let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+            mac.update(body.as_bytes());
+            let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+
+            Request::post("/webhooks/github")
+                .header("X-GitHub-Event", "push")
+                .header("X-Hub-Signature-256", signature)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        assert_eq!(get_stars(&app).await.1, "MISS");
+        assert_eq!(get_stars(&app).await.1, "HIT");
+
+        // Pushes that don't change the stars file keep the cache
+        let (status, _, body) = send(&app, push(&["README.md"])).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "Invalidated 0 cache entries");
+        assert_eq!(get_stars(&app).await.1, "HIT");
+
+        let (_, _, body) = send(&app, push(&["stars.txt"])).await;
+        assert_eq!(body, "Invalidated 1 cache entries");
+        assert_eq!(get_stars(&app).await.1, "MISS");
+        assert_eq!(github.requests(), 2);
+
+        // Unsigned requests are rejected
+        let mut request = push(&["stars.txt"]);
+        request.headers_mut().remove("X-Hub-Signature-256");
+        assert_eq!(send(&app, request).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes() {
+        let github = StubGitHub::start().await;
+        let app = test_router(AppState::new(&test_config(&github.base_url)).unwrap());
+        let admin = |method: Method, uri: &str, token: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Disabled without a token
+        let (status, _, _) = send(&app, admin(Method::GET, "/admin/cache", "")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let config = Config {
+            admin_token: "token".to_string(),
+            ..test_config(&github.base_url)
+        };
+        let app = test_router(AppState::new(&config).unwrap());
+        assert_eq!(get_stars(&app).await.1, "MISS");
+
+        let (status, _, _) = send(&app, admin(Method::GET, "/admin/cache", "wrong")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _, body) = send(&app, admin(Method::GET, "/admin/cache", "token")).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["entries"][0]["key"], "github:user/repo/main/stars.txt");
+        assert!(body["entries"][0]["size_bytes"].as_u64().unwrap() > 0);
+
+        let (_, _, body) = send(&app, admin(Method::GET, "/admin/rate-limits", "token")).await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["algorithm"], "fixed-window");
+        assert_eq!(body["entries"][0]["count"], 1);
+
+        // Purging needs either a key or a prefix
+        let (status, _, _) = send(&app, admin(Method::DELETE, "/admin/cache", "token")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Purging a whole user
+        let (status, _, body) = send(
+            &app,
+            admin(Method::DELETE, "/admin/cache?prefix=github:user/", "token"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"purged":1}"#);
+        assert_eq!(get_stars(&app).await.1, "MISS");
+
+        let (status, _, body) =
+            send(&app, admin(Method::DELETE, "/admin/error-cache", "token")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"purged":0}"#);
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache_survives_restart() {
+        let github = StubGitHub::start().await;
+        let cache_dir =
+            std::env::temp_dir().join(format!("advent-stars-restart-{}", Uuid::new_v4()));
+        let config = Config {
+            cache_backend: CacheBackend::Disk,
+            cache_dir: cache_dir.clone(),
+            ..test_config(&github.base_url)
+        };
+
+        let app = test_router(AppState::new(&config).unwrap());
+        assert_eq!(get_stars(&app).await.1, "MISS");
+
+        // A new process with the same cache directory
+        let app = test_router(AppState::new(&config).unwrap());
+        let (status, x_cache, body) = get_stars(&app).await;
+        assert_eq!((status, x_cache.as_str()), (StatusCode::OK, "HIT"));
+        assert!(body.contains("<svg"));
+        assert_eq!(github.requests(), 1);
+
+        std::fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_render() {
+        let config = Config {
+            render_max_body_size: 256,
+            render_rate_limit_max_requests: 3,
+            ..test_config("http://127.0.0.1:1")
+        };
+        let app = test_router(AppState::new(&config).unwrap());
+        let render = |uri: &str, content_type: &str, body: String| {
+            Request::post(uri)
+                .header("Content-Type", content_type)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(render(
+                "/render?theme=aoc-classic",
+                "text/plain",
+                STARS.to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "image/svg+xml");
+
+        let json = serde_json::json!({"2024": [2, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]});
+        let response = app
+            .clone()
+            .oneshot(render(
+                "/render?format=png",
+                "application/json",
+                json.to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "image/png");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.starts_with(b"\x89PNG"));
+
+        // Bodies over the limit are rejected, whether or not their length is known up front
+        let oversize = STARS.repeat(5);
+        let mut with_length = render("/render", "text/plain", oversize.clone());
+        with_length
+            .headers_mut()
+            .insert("Content-Length", oversize.len().into());
+        with_length
+            .headers_mut()
+            .insert("Accept", HeaderValue::from_static("image/*"));
+        for request in [render("/render", "text/plain", oversize), with_length] {
+            let (status, _, body) = send(&app, request).await;
+            assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"]["code"], "body_too_large");
+        }
+
+        // Browser tools can post JSON, after a preflight
+        let preflight = Request::options("/render")
+            .header("Origin", "https://tools.example.com")
+            .header("Access-Control-Request-Method", "POST")
+            .header("Access-Control-Request-Headers", "content-type")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(preflight).await.unwrap();
+        let allowed = |header: &str| response.headers()[header].to_str().unwrap().to_lowercase();
+        assert!(allowed("Access-Control-Allow-Methods").contains("post"));
+        assert!(allowed("Access-Control-Allow-Headers").contains("content-type"));
+
+        // The render endpoint has its own limit per client
+        let (status, _, body) =
+            send(&app, render("/render", "text/plain", STARS.to_string())).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["code"], "rate_limited");
+    }
+
+    #[tokio::test]
+    async fn test_data() {
+        let github = StubGitHub::start().await;
+        let app = test_router(AppState::new(&test_config(&github.base_url)).unwrap());
+        let request = || {
+            Request::get("/data/user/repo/main/stars.json")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (status, x_cache, body) = send(&app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(x_cache, "MISS");
+        let data: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(data["total_stars"], 5);
+        assert_eq!(data["max_stars"], 50);
+        assert_eq!(data["completion_percent"], 10.0);
+        assert_eq!(data["years"][0]["year"], 2024);
+        assert_eq!(data["years"][0]["days"][2], 1);
+
+        // The response is cached apart from the rendered grid of the same file
+        let (status, _, _) = get_stars(&app).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, x_cache, _) = send(&app, request()).await;
+        assert_eq!(x_cache, "HIT");
+        assert_eq!(github.requests(), 2);
+
+        let (status, _, _) = send(
+            &app,
+            Request::get("/data/user/repo/main/other.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_badge_year_not_found() {
+        let github = StubGitHub::start().await;
+        let config = Config {
+            rate_limit_max_requests: 2,
+            ..test_config(&github.base_url)
+        };
+        let state = AppState::new(&config).unwrap();
+        let app = test_router(state.clone());
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+        // The missing year is cached for the badge alone, without counting against the rate limit
+        for _ in 0..3 {
+            let (status, _, body) =
+                send(&app, get("/badge/user/repo/main/stars.svg?year=2015")).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert!(body.contains("Year 2015 not found"), "{}", body);
+        }
+        assert_eq!(github.requests(), 1);
+        assert!(state
+            .error_cache
+            .contains_key("github:user/repo/main/stars.txt#badge:2015"));
+
+        let (status, _, _) = send(&app, get("/badge/user/repo/main/stars.svg?year=2024")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_shields_errors() {
+        let github = StubGitHub::start().await;
+        let app = test_router(AppState::new(&test_config(&github.base_url)).unwrap());
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+        let (status, _, body) = send(&app, get("/shields/user/repo/main/stars.json")).await;
+        assert_eq!(status, StatusCode::OK);
+        let endpoint: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(endpoint["message"], "⭐ 5");
+        assert!(endpoint.get("isError").is_none());
+
+        for _ in 0..2 {
+            let (status, _, body) =
+                send(&app, get("/shields/user/repo/main/stars.json?year=2015")).await;
+            assert_eq!(status, StatusCode::OK);
+            let endpoint: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(endpoint["schemaVersion"], 1);
+            assert_eq!(endpoint["label"], "2015");
+            assert_eq!(endpoint["isError"], true);
+            assert!(endpoint["message"]
+                .as_str()
+                .unwrap()
+                .contains("Year 2015 not found"));
+        }
+        // The missing year is cached like any other response, only its first request is fetched
+        assert_eq!(github.requests(), 2);
+
+        let (status, _, body) = send(&app, get("/shields/user/repo/main/stars.json?year=x")).await;
+        assert_eq!(status, StatusCode::OK);
+        let endpoint: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(endpoint["label"], "AoC");
+        assert_eq!(endpoint["isError"], true);
+    }
+
+    #[tokio::test]
+    async fn test_default_branch() {
+        let github = StubGitHub::start().await;
+        let state = AppState::new(&test_config(&github.base_url)).unwrap();
+        let app = test_router(state.clone());
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+        let (status, x_cache, _) = send(&app, get("/stars/user/repo/HEAD/stars.svg")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(x_cache, "MISS");
+        // The resolved branch and the file
+        assert_eq!(github.requests(), 2);
+
+        // Responses are cached under the resolved branch, and the resolution is cached apart
+        let (_, x_cache, _) = get_stars(&app).await;
+        assert_eq!(x_cache, "HIT");
+        let (status, _, _) = send(&app, get("/badge/user/repo/HEAD/stars.svg")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(github.requests(), 3);
+        assert_eq!(
+            state.default_branches.get("github:user/repo"),
+            Some("main".to_string())
+        );
+
+        // Concurrent requests share the lookup, and then the fetch
+        state.default_branches.invalidate_all();
+        github.delay_ms.store(100, Ordering::SeqCst);
+        let requests: Vec<_> = (0..5)
+            .map(|cell| {
+                let app = app.clone();
+                let uri = format!("/stars/user/repo/HEAD/stars.svg?cell={}", 20 + cell);
+                tokio::spawn(async move { send(&app, get(&uri)).await })
+            })
+            .collect();
+        for request in requests {
+            let (status, _, _) = request.await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+        }
+        assert_eq!(github.requests(), 5);
+        github.delay_ms.store(0, Ordering::SeqCst);
+
+        // Failing to resolve the branch is an error like any other, and cached
+        let (status, _, _) = send(&app, get("/stars/user/missing/HEAD/stars.svg")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(state.error_cache.contains_key("github:user/missing"));
+
+        state.default_branches.invalidate_all();
+        github.failing.store(true, Ordering::SeqCst);
+        let (status, _, body) = send(&app, get("/data/user/repo/HEAD/stars.json")).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.contains("Unexpected status 502"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_max_staleness() {
+        let github = StubGitHub::start().await;
+        let config = Config {
+            cache_ttl_secs: 0,
+            max_stale_secs: 0,
+            ..test_config(&github.base_url)
+        };
+        let app = test_router(AppState::new(&config).unwrap());
+
+        // Responses too stale to serve are fetched again
+        for _ in 0..2 {
+            let (status, x_cache, _) = get_stars(&app).await;
+            assert_eq!((status, x_cache.as_str()), (StatusCode::OK, "MISS"));
+        }
+        assert_eq!(github.requests(), 2);
+    }
+}
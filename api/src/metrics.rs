@@ -0,0 +1,256 @@
+//! Prometheus metrics for the API, served at `/metrics`.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header::CONTENT_TYPE,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+/// Which of the caches a lookup went to
+#[derive(Debug, Clone, Copy)]
+pub enum CacheKind {
+    /// Rendered responses
+    Response,
+    /// Errors fetching or validating files
+    Error,
+    /// Default branches of repositories
+    DefaultBranch,
+}
+
+/// The result of a cache lookup
+#[derive(Debug, Clone, Copy)]
+pub enum CacheResult {
+    Hit,
+    Miss,
+    Stale,
+}
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    cache_lookups: IntCounterVec,
+    github_request_duration: Histogram,
+    github_errors: IntCounterVec,
+    github_rate_limit_remaining: IntGauge,
+    rate_limited: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "aoc_stars_requests_total",
+                "Requests handled, by route and status",
+            ),
+            &["route", "status"],
+        )
+        .unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "aoc_stars_cache_lookups_total",
+                "Cache lookups, by cache and result",
+            ),
+            &["cache", "result"],
+        )
+        .unwrap();
+        let github_request_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "aoc_stars_github_request_duration_seconds",
+                "Time taken by requests to GitHub",
+            )
+            .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+        )
+        .unwrap();
+        let github_errors = IntCounterVec::new(
+            Opts::new(
+                "aoc_stars_github_errors_total",
+                "Failed requests to GitHub, by the status or `request` when there was no response",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let github_rate_limit_remaining = IntGauge::new(
+            "aoc_stars_github_rate_limit_remaining",
+            "The last x-ratelimit-remaining reported by GitHub",
+        )
+        .unwrap();
+        let rate_limited = IntCounter::new(
+            "aoc_stars_rate_limited_total",
+            "Requests rejected by the rate limit",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+        registry
+            .register(Box::new(github_request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(github_errors.clone())).unwrap();
+        registry
+            .register(Box::new(github_rate_limit_remaining.clone()))
+            .unwrap();
+        registry.register(Box::new(rate_limited.clone())).unwrap();
+
+        Self {
+            registry,
+            requests,
+            cache_lookups,
+            github_request_duration,
+            github_errors,
+            github_rate_limit_remaining,
+            rate_limited,
+        }
+    }
+
+    pub fn record_cache_lookup(&self, cache: CacheKind, result: CacheResult) {
+        let cache = match cache {
+            CacheKind::Response => "response",
+            CacheKind::Error => "error",
+            CacheKind::DefaultBranch => "default_branch",
+        };
+        let result = match result {
+            CacheResult::Hit => "hit",
+            CacheResult::Miss => "miss",
+            CacheResult::Stale => "stale",
+        };
+        self.cache_lookups.with_label_values(&[cache, result]).inc();
+    }
+
+    /// Record a request to GitHub that took `started.elapsed()`, with the status of the response,
+    /// or `None` if the request failed without one
+    pub fn record_github_request(&self, started: Instant, status: Option<u16>) {
+        self.github_request_duration
+            .observe(started.elapsed().as_secs_f64());
+
+        match status {
+            // 304s are successful revalidations
+            Some(status) if status < 400 => {}
+            Some(status) => self
+                .github_errors
+                .with_label_values(&[&status.to_string()])
+                .inc(),
+            None => self.github_errors.with_label_values(&["request"]).inc(),
+        }
+    }
+
+    pub fn set_github_rate_limit_remaining(&self, remaining: i64) {
+        self.github_rate_limit_remaining.set(remaining);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.inc();
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+/// Count the requests to each route by status. Added as a route layer, so the matched route is
+/// known and unmatched paths don't create new labels.
+pub async fn track_requests(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let response = next.run(request).await;
+    metrics
+        .requests
+        .with_label_values(&[&route, response.status().as_str()])
+        .inc();
+    response
+}
+
+pub async fn handle_metrics(State(metrics): State<Arc<Metrics>>) -> Response {
+    (
+        [(CONTENT_TYPE, TextEncoder::new().format_type().to_string())],
+        metrics.render(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record_cache_lookup(CacheKind::Response, CacheResult::Stale);
+        metrics.record_cache_lookup(CacheKind::Error, CacheResult::Miss);
+        metrics.record_github_request(Instant::now(), Some(200));
+        metrics.record_github_request(Instant::now(), Some(304));
+        metrics.record_github_request(Instant::now(), Some(502));
+        metrics.record_github_request(Instant::now(), None);
+        metrics.set_github_rate_limit_remaining(4999);
+        metrics.record_rate_limited();
+
+        let rendered = metrics.render();
+        for line in [
+            r#"aoc_stars_cache_lookups_total{cache="response",result="stale"} 1"#,
+            r#"aoc_stars_cache_lookups_total{cache="error",result="miss"} 1"#,
+            "aoc_stars_github_request_duration_seconds_count 4",
+            r#"aoc_stars_github_errors_total{reason="502"} 1"#,
+            r#"aoc_stars_github_errors_total{reason="request"} 1"#,
+            "aoc_stars_github_rate_limit_remaining 4999",
+            "aoc_stars_rate_limited_total 1",
+        ] {
+            assert!(
+                rendered.contains(line),
+                "{} missing from\n{}",
+                line,
+                rendered
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_track_requests() {
+        let metrics = Arc::new(Metrics::new());
+        let app = Router::new()
+            .route("/stars/:user", get(|| async { "stars" }))
+            .route("/metrics", get(handle_metrics))
+            .route_layer(middleware::from_fn_with_state(
+                metrics.clone(),
+                track_requests,
+            ))
+            .with_state(metrics.clone());
+
+        for path in ["/stars/a", "/stars/b", "/missing"] {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(body.contains(r#"aoc_stars_requests_total{route="/stars/:user",status="200"} 2"#));
+        assert!(!body.contains("/missing"));
+    }
+}
@@ -0,0 +1,204 @@
+//! Query parameters controlling how the stars grid is rendered.
+
+use std::collections::HashSet;
+
+use svg::{RenderOptions, Theme, MAX_CELL_SIZE, MIN_CELL_SIZE};
+
+use crate::{AppError, AppResult, ErrorKind};
+
+const MAX_TITLE_LENGTH: usize = 64;
+
+fn invalid(key: &str, reason: impl std::fmt::Display) -> AppError {
+    AppError::ValidationError(
+        ErrorKind::Query,
+        format!("Invalid query parameter `{}`: {}", key, reason),
+    )
+}
+
+fn parse_years(value: &str) -> Result<(usize, usize), String> {
+    let parse = |year: &str| {
+        year.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("{} is not a year", year))
+    };
+
+    let (start, end) = match value.split_once('-') {
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => {
+            let year = parse(value)?;
+            (year, year)
+        }
+    };
+
+    if start > end {
+        return Err(format!("{} is after {}", start, end));
+    }
+    Ok((start, end))
+}
+
+/// Strictly parse the render options from the query parameters, rejecting unknown or repeated
+/// parameters as well as invalid values
+pub fn parse_render_options(params: &[(String, String)]) -> AppResult<RenderOptions> {
+    let mut options = RenderOptions::default();
+    let mut seen = HashSet::new();
+
+    for (key, value) in params {
+        if !seen.insert(key.as_str()) {
+            return Err(invalid(key, "given more than once"));
+        }
+
+        match key.as_str() {
+            "theme" => options.theme = value.parse::<Theme>().map_err(|e| invalid(key, e))?,
+            "cell" => {
+                let cell_size = value
+                    .parse::<i32>()
+                    .ok()
+                    .filter(|size| (MIN_CELL_SIZE..=MAX_CELL_SIZE).contains(size))
+                    .ok_or_else(|| {
+                        invalid(
+                            key,
+                            format!(
+                                "expected a number between {} and {}",
+                                MIN_CELL_SIZE, MAX_CELL_SIZE
+                            ),
+                        )
+                    })?;
+                options.cell_size = cell_size;
+            }
+            "hide_totals" => {
+                options.hide_totals = match value.as_str() {
+                    "1" | "true" => true,
+                    "0" | "false" => false,
+                    _ => return Err(invalid(key, "expected 1, 0, true or false")),
+                }
+            }
+            "years" => {
+                let (start, end) = parse_years(value).map_err(|e| invalid(key, e))?;
+                options.years = Some(start..=end);
+            }
+            "title" => {
+                if value.trim().is_empty() {
+                    return Err(invalid(key, "must not be empty"));
+                }
+                if value.chars().count() > MAX_TITLE_LENGTH {
+                    return Err(invalid(
+                        key,
+                        format!("must be at most {} characters", MAX_TITLE_LENGTH),
+                    ));
+                }
+                options.title = Some(value.clone());
+            }
+            _ => {
+                return Err(AppError::ValidationError(
+                    ErrorKind::Query,
+                    format!("Unknown query parameter `{}`", key),
+                ))
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+/// A canonical description of the non-default options, to tell rendered variants apart in the
+/// cache. The default options have no variant, so they share the plain cache key.
+pub fn render_variant(options: &RenderOptions) -> Option<String> {
+    let defaults = RenderOptions::default();
+    let mut parts = Vec::new();
+
+    if options.theme != defaults.theme {
+        parts.push(format!("theme={}", options.theme.name()));
+    }
+    if options.cell_size != defaults.cell_size {
+        parts.push(format!("cell={}", options.cell_size));
+    }
+    if options.hide_totals {
+        parts.push("hide_totals=1".to_string());
+    }
+    if let Some(years) = &options.years {
+        parts.push(format!("years={}-{}", years.start(), years.end()));
+    }
+    if let Some(title) = &options.title {
+        parts.push(format!("title={}", title));
+    }
+
+    (!parts.is_empty()).then(|| parts.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_all_options() {
+        let options = parse_render_options(&params(&[
+            ("theme", "aoc-classic"),
+            ("cell", "24"),
+            ("hide_totals", "1"),
+            ("years", "2019-2024"),
+            ("title", "My stars"),
+        ]))
+        .unwrap();
+
+        assert_eq!(options.theme, Theme::AocClassic);
+        assert_eq!(options.cell_size, 24);
+        assert!(options.hide_totals);
+        assert_eq!(options.years, Some(2019..=2024));
+        assert_eq!(options.title.as_deref(), Some("My stars"));
+        assert_eq!(
+            render_variant(&options).unwrap(),
+            "theme=aoc-classic&cell=24&hide_totals=1&years=2019-2024&title=My stars"
+        );
+    }
+
+    #[test]
+    fn test_default_options_have_no_variant() {
+        let options = parse_render_options(&[]).unwrap();
+        assert_eq!(options, RenderOptions::default());
+        assert_eq!(render_variant(&options), None);
+
+        let options = parse_render_options(&params(&[("hide_totals", "0")])).unwrap();
+        assert_eq!(render_variant(&options), None);
+    }
+
+    #[test]
+    fn test_single_year() {
+        let options = parse_render_options(&params(&[("years", "2024")])).unwrap();
+        assert_eq!(options.years, Some(2024..=2024));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        for pairs in [
+            vec![("theme", "neon")],
+            vec![("cell", "8")],
+            vec![("cell", "100")],
+            vec![("cell", "big")],
+            vec![("hide_totals", "yes")],
+            vec![("years", "2024-2019")],
+            vec![("years", "last")],
+            vec![("title", " ")],
+            vec![("colour", "red")],
+            vec![("cell", "20"), ("cell", "24")],
+        ] {
+            assert!(
+                matches!(
+                    parse_render_options(&params(&pairs)),
+                    Err(AppError::ValidationError(ErrorKind::Query, _))
+                ),
+                "{:?} should be rejected",
+                pairs
+            );
+        }
+
+        let long_title = "a".repeat(MAX_TITLE_LENGTH + 1);
+        assert!(parse_render_options(&params(&[("title", &long_title)])).is_err());
+    }
+}
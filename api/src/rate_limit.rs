@@ -0,0 +1,255 @@
+//! Per client rate limiting of upstream fetches.
+//!
+//! The state is kept in a concurrent cache, so checks don't contend on a global lock, and entries
+//! are evicted once idle for a whole window or when the cache is full.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use moka::sync::Cache;
+
+/// How requests are counted against the limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Allow `max_requests` in each window, starting at the first request of the window
+    #[default]
+    FixedWindow,
+    /// Allow bursts of up to `max_requests`, refilling at `max_requests` per window
+    TokenBucket,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::FixedWindow => "fixed-window",
+            Self::TokenBucket => "token-bucket",
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed-window" => Ok(Self::FixedWindow),
+            "token-bucket" => Ok(Self::TokenBucket),
+            _ => Err(format!(
+                "Unknown rate limit algorithm {}, expected one of: fixed-window, token-bucket",
+                s
+            )),
+        }
+    }
+}
+
+/// The rate limiting state of a single key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bucket {
+    Window { start: Instant, count: u32 },
+    Tokens { tokens: f64, updated: Instant },
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    pub algorithm: Algorithm,
+    pub window_size: Duration,
+    pub max_requests: u32,
+    buckets: Cache<String, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        algorithm: Algorithm,
+        window_size: Duration,
+        max_requests: u32,
+        max_entries: u64,
+    ) -> Self {
+        // An entry idle for a whole window is equivalent to a fresh one with either algorithm, so
+        // it's safe to evict
+        let buckets = Cache::builder()
+            .time_to_idle(window_size)
+            .max_capacity(max_entries)
+            .build();
+
+        Self {
+            algorithm,
+            window_size,
+            max_requests,
+            buckets,
+        }
+    }
+
+    /// The state of every tracked key
+    pub fn entries(&self) -> Vec<(String, Bucket)> {
+        self.buckets
+            .iter()
+            .map(|(key, bucket)| (key.to_string(), bucket))
+            .collect()
+    }
+
+    /// Count a request for the key, returning whether it's allowed
+    pub fn check(&self, key: &str) -> bool {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> bool {
+        let mut allowed = false;
+        self.buckets.entry_by_ref(key).and_upsert_with(|entry| {
+            let (bucket, is_allowed) = self.next(entry.map(|entry| entry.into_value()), now);
+            allowed = is_allowed;
+            bucket
+        });
+        allowed
+    }
+
+    /// The state of the key after a request at `now`, and whether the request is allowed
+    fn next(&self, bucket: Option<Bucket>, now: Instant) -> (Bucket, bool) {
+        match (self.algorithm, bucket) {
+            (Algorithm::FixedWindow, Some(Bucket::Window { start, count }))
+                if now.saturating_duration_since(start) <= self.window_size =>
+            {
+                if count < self.max_requests {
+                    (
+                        Bucket::Window {
+                            start,
+                            count: count + 1,
+                        },
+                        true,
+                    )
+                } else {
+                    (Bucket::Window { start, count }, false)
+                }
+            }
+            // A new window, either for a new key or once the previous window is over
+            (Algorithm::FixedWindow, _) => (
+                Bucket::Window {
+                    start: now,
+                    count: 1,
+                },
+                self.max_requests > 0,
+            ),
+            (Algorithm::TokenBucket, bucket) => {
+                let capacity = self.max_requests as f64;
+                let tokens = match bucket {
+                    Some(Bucket::Tokens { tokens, updated }) => {
+                        let refill = now.saturating_duration_since(updated).as_secs_f64()
+                            / self.window_size.as_secs_f64()
+                            * capacity;
+                        (tokens + refill).min(capacity)
+                    }
+                    _ => capacity,
+                };
+
+                if tokens >= 1.0 {
+                    (
+                        Bucket::Tokens {
+                            tokens: tokens - 1.0,
+                            updated: now,
+                        },
+                        true,
+                    )
+                } else {
+                    (
+                        Bucket::Tokens {
+                            tokens,
+                            updated: now,
+                        },
+                        false,
+                    )
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of keys currently being tracked
+    fn tracked(limiter: &RateLimiter) -> u64 {
+        limiter.buckets.run_pending_tasks();
+        limiter.buckets.entry_count()
+    }
+
+    fn limiter(algorithm: Algorithm, max_requests: u32) -> RateLimiter {
+        RateLimiter::new(algorithm, Duration::from_secs(60), max_requests, 100)
+    }
+
+    #[test]
+    fn test_algorithm_from_str() {
+        assert_eq!("fixed-window".parse(), Ok(Algorithm::FixedWindow));
+        assert_eq!("token-bucket".parse(), Ok(Algorithm::TokenBucket));
+        assert!("leaky-bucket".parse::<Algorithm>().is_err());
+    }
+
+    #[test]
+    fn test_fixed_window_rollover() {
+        let limiter = limiter(Algorithm::FixedWindow, 2);
+        let start = Instant::now();
+
+        assert!(limiter.check_at("ip:key", start));
+        assert!(limiter.check_at("ip:key", start + Duration::from_secs(1)));
+        assert!(!limiter.check_at("ip:key", start + Duration::from_secs(59)));
+        // Other keys have their own window
+        assert!(limiter.check_at("ip:other", start + Duration::from_secs(59)));
+
+        // The count resets once the window is over
+        let next_window = start + Duration::from_secs(61);
+        assert!(limiter.check_at("ip:key", next_window));
+        assert!(limiter.check_at("ip:key", next_window));
+        assert!(!limiter.check_at("ip:key", next_window));
+    }
+
+    #[test]
+    fn test_token_bucket_refill() {
+        let limiter = limiter(Algorithm::TokenBucket, 2);
+        let start = Instant::now();
+
+        // The bucket starts full, allowing a burst
+        assert!(limiter.check_at("ip:key", start));
+        assert!(limiter.check_at("ip:key", start));
+        assert!(!limiter.check_at("ip:key", start));
+
+        // Refilling one token takes half the window
+        assert!(!limiter.check_at("ip:key", start + Duration::from_secs(20)));
+        assert!(limiter.check_at("ip:key", start + Duration::from_secs(31)));
+        assert!(!limiter.check_at("ip:key", start + Duration::from_secs(32)));
+
+        // The bucket never holds more than the maximum
+        let later = start + Duration::from_secs(600);
+        assert!(limiter.check_at("ip:key", later));
+        assert!(limiter.check_at("ip:key", later));
+        assert!(!limiter.check_at("ip:key", later));
+    }
+
+    #[test]
+    fn test_idle_entries_evicted() {
+        let limiter = RateLimiter::new(Algorithm::FixedWindow, Duration::from_millis(50), 1, 100);
+
+        assert!(limiter.check("ip:key"));
+        assert!(!limiter.check("ip:key"));
+        assert_eq!(tracked(&limiter), 1);
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(tracked(&limiter), 0);
+        assert!(limiter.check("ip:key"));
+    }
+
+    #[test]
+    fn test_capacity_bound() {
+        let limiter = RateLimiter::new(Algorithm::TokenBucket, Duration::from_secs(60), 5, 10);
+
+        for i in 0..100 {
+            limiter.check(&format!("ip-{}:key", i));
+        }
+        assert!(tracked(&limiter) <= 10);
+    }
+}
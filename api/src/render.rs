@@ -0,0 +1,409 @@
+//! Rendering stars data posted in the request body, for tools that already have the data and
+//! only want the image.
+//!
+//! Errors are returned as JSON, so tools can tell what was wrong with the data without parsing
+//! the message.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, OnceLock};
+
+use axum::{
+    body::Bytes,
+    extract::{rejection::BytesRejection, Query, State},
+    http::{
+        header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use resvg::{tiny_skia, usvg};
+use serde::Serialize;
+use svg::{generate_svg_with_options, validate_input, ValidationError, Years};
+use tracing::{error, warn};
+
+use crate::params::parse_render_options;
+use crate::{AppError, AppState, RequestContext};
+
+/// The image format of the response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Svg,
+    Png,
+}
+
+impl Format {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Svg => "image/svg+xml",
+            Self::Png => "image/png",
+        }
+    }
+
+    /// The `format` parameter takes precedence, otherwise the image type in the `Accept` header
+    /// with the highest `q` value is used, the earlier one on a tie, defaulting to SVG
+    fn negotiate(param: Option<&str>, headers: &HeaderMap) -> Result<Self, RenderError> {
+        match param {
+            Some("svg") => return Ok(Self::Svg),
+            Some("png") => return Ok(Self::Png),
+            Some(other) => {
+                return Err(RenderError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_parameter",
+                    format!("Invalid `format`: {}, expected svg or png", other),
+                ))
+            }
+            None => {}
+        }
+
+        let accept = headers
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let mut format = None;
+        let mut best = 0.0;
+        for media_type in accept.split(',') {
+            let mut parts = media_type.split(';');
+            let candidate = match parts.next().unwrap_or_default().trim() {
+                "image/svg+xml" => Self::Svg,
+                "image/png" => Self::Png,
+                _ => continue,
+            };
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            // `q=0` means the type isn't acceptable at all
+            if quality > best {
+                format = Some(candidate);
+                best = quality;
+            }
+        }
+        Ok(format.unwrap_or(Self::Svg))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorDetail {
+    /// Stable identifier of the kind of error
+    code: &'static str,
+    message: String,
+    /// Line of the stars text the error is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    /// Year the error is in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    year: Option<usize>,
+}
+
+#[derive(Debug)]
+struct RenderError {
+    status: StatusCode,
+    detail: ErrorDetail,
+}
+
+impl RenderError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            detail: ErrorDetail {
+                code,
+                message: message.into(),
+                line: None,
+                year: None,
+            },
+        }
+    }
+
+    fn invalid_data(error: &ValidationError) -> Self {
+        let (code, line, year) = match error {
+            ValidationError::EmptyInput => ("empty_input", None, None),
+            ValidationError::InvalidLineFormat { line, .. } => ("invalid_line", Some(*line), None),
+            ValidationError::InvalidYear { line, .. } => ("invalid_year", Some(*line), None),
+            ValidationError::InvalidDayCount { year, .. } => {
+                ("invalid_day_count", None, Some(*year))
+            }
+            ValidationError::InvalidStarValue { year } => ("invalid_star_value", None, Some(*year)),
+            ValidationError::ParseError { year, .. } => ("invalid_day", None, Some(*year)),
+        };
+
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            detail: ErrorDetail {
+                code,
+                message: error.to_string(),
+                line,
+                year,
+            },
+        }
+    }
+}
+
+impl IntoResponse for RenderError {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct Body {
+            error: ErrorDetail,
+        }
+
+        (self.status, Json(Body { error: self.detail })).into_response()
+    }
+}
+
+/// `RequestBodyLimitLayer` turns away bodies declared over the limit with a plain text 413 before
+/// the handler runs, so give them the same error as bodies found to be over it while reading
+pub async fn body_limit_error(response: Response) -> Response {
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE || is_json {
+        return response;
+    }
+
+    RenderError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "body_too_large",
+        "Request body is over the size limit",
+    )
+    .into_response()
+}
+
+/// Parse the stars data, either in the stars file format or as JSON mapping years to days
+fn parse_years(headers: &HeaderMap, body: &[u8]) -> Result<Years, RenderError> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim);
+
+    match content_type {
+        None | Some("text/plain") => {
+            let content = std::str::from_utf8(body).map_err(|e| {
+                RenderError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_body",
+                    format!("Body is not valid UTF-8: {}", e),
+                )
+            })?;
+            validate_input(content).map_err(|e| RenderError::invalid_data(&e))
+        }
+        Some("application/json") => {
+            let years: BTreeMap<String, Vec<u8>> = serde_json::from_slice(body).map_err(|e| {
+                RenderError::new(StatusCode::BAD_REQUEST, "invalid_json", e.to_string())
+            })?;
+
+            // Validate through the stars file format, so both formats have the same rules. The
+            // years are parsed first, so keys can't smuggle in extra lines.
+            let mut lines = Vec::new();
+            for (year, days) in &years {
+                let year: usize = year.trim().parse().map_err(|_| {
+                    RenderError::new(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_year",
+                        format!("Invalid year: {}", year),
+                    )
+                })?;
+                let days: Vec<_> = days.iter().map(u8::to_string).collect();
+                lines.push(format!("{}: {}", year, days.join(",")));
+            }
+
+            validate_input(&lines.join("\n")).map_err(|e| {
+                let mut error = RenderError::invalid_data(&e);
+                // The lines are of the generated text, not the request
+                error.detail.line = None;
+                error
+            })
+        }
+        Some(other) => Err(RenderError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            format!(
+                "Unsupported content type {}, expected text/plain or application/json",
+                other
+            ),
+        )),
+    }
+}
+
+/// Fonts are loaded once, the first time a PNG is rendered
+fn fonts() -> Arc<usvg::fontdb::Database> {
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut fonts = usvg::fontdb::Database::new();
+            fonts.load_system_fonts();
+            Arc::new(fonts)
+        })
+        .clone()
+}
+
+fn render_png(svg: &str) -> Result<Vec<u8>, String> {
+    let options = usvg::Options {
+        fontdb: fonts(),
+        ..usvg::Options::default()
+    };
+    let tree = usvg::Tree::from_str(svg, &options).map_err(|e| e.to_string())?;
+
+    let size = tree.size().to_int_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| format!("Invalid image size {}x{}", size.width(), size.height()))?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+
+    pixmap.encode_png().map_err(|e| e.to_string())
+}
+
+pub async fn handle_render(
+    State(state): State<AppState>,
+    Query(params): Query<Vec<(String, String)>>,
+    request: RequestContext,
+    body: Result<Bytes, BytesRejection>,
+) -> Response {
+    match render(&state, params, &request, body).await {
+        Ok(response) => response,
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn render(
+    state: &AppState,
+    mut params: Vec<(String, String)>,
+    request: &RequestContext,
+    body: Result<Bytes, BytesRejection>,
+) -> Result<Response, RenderError> {
+    let client_ip = request.client_ip.to_string();
+    if !state.render_rate_limiter.check(&client_ip) {
+        state.metrics.record_rate_limited();
+        warn!(client_ip = %client_ip, "Render rate limit exceeded");
+        return Err(RenderError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            "Rate limit exceeded",
+        ));
+    }
+
+    let format_param = params
+        .iter()
+        .position(|(key, _)| key == "format")
+        .map(|index| params.remove(index).1);
+    let format = Format::negotiate(format_param.as_deref(), &request.headers)?;
+    let options = parse_render_options(&params).map_err(|e| match e {
+        AppError::ValidationError(_, message) => {
+            RenderError::new(StatusCode::BAD_REQUEST, "invalid_parameter", message)
+        }
+        e => RenderError::new(StatusCode::BAD_REQUEST, "invalid_parameter", e.to_string()),
+    })?;
+
+    let body = body.map_err(|e| {
+        let code = match e.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => "body_too_large",
+            _ => "invalid_body",
+        };
+        RenderError::new(e.status(), code, e.body_text())
+    })?;
+    let years = parse_years(&request.headers, &body)?;
+
+    let svg = generate_svg_with_options(years, &options);
+    let content = match format {
+        Format::Svg => svg.into_bytes(),
+        Format::Png => tokio::task::spawn_blocking(move || render_png(&svg))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|png| png)
+            .map_err(|e| {
+                error!(error = %e, "Failed to render PNG");
+                RenderError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "render_failed",
+                    "Failed to render PNG",
+                )
+            })?,
+    };
+
+    Ok((
+        [
+            (CONTENT_TYPE, format.content_type()),
+            (CACHE_CONTROL, "no-store"),
+        ],
+        content,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const STARS: &str = "2024: 2,2,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0";
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_negotiate_format() {
+        let none = HeaderMap::new();
+        assert_eq!(Format::negotiate(None, &none).unwrap(), Format::Svg);
+        assert_eq!(Format::negotiate(Some("png"), &none).unwrap(), Format::Png);
+        assert!(Format::negotiate(Some("gif"), &none).is_err());
+
+        let png = headers(&[("accept", "image/png, image/svg+xml;q=0.9")]);
+        assert_eq!(Format::negotiate(None, &png).unwrap(), Format::Png);
+        // The parameter wins over the header
+        assert_eq!(Format::negotiate(Some("svg"), &png).unwrap(), Format::Svg);
+
+        let browser = headers(&[("accept", "text/html, */*")]);
+        assert_eq!(Format::negotiate(None, &browser).unwrap(), Format::Svg);
+
+        // Ranked by `q` rather than by order, with ties going to the earlier type
+        let prefers_svg = headers(&[("accept", "image/png;q=0.1, image/svg+xml")]);
+        assert_eq!(Format::negotiate(None, &prefers_svg).unwrap(), Format::Svg);
+        let tie = headers(&[("accept", "image/png;q=0.5, image/svg+xml;q=0.5")]);
+        assert_eq!(Format::negotiate(None, &tie).unwrap(), Format::Png);
+        let no_svg = headers(&[("accept", "image/svg+xml;q=0, image/png;q=0.2")]);
+        assert_eq!(Format::negotiate(None, &no_svg).unwrap(), Format::Png);
+    }
+
+    #[test]
+    fn test_parse_years() {
+        let text = headers(&[("content-type", "text/plain; charset=utf-8")]);
+        assert_eq!(parse_years(&text, STARS.as_bytes()).unwrap()[0].0, 2024);
+
+        let json = headers(&[("content-type", "application/json")]);
+        let body = r#"{"2024": [2,2,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]}"#;
+        assert_eq!(
+            parse_years(&json, body.as_bytes()).unwrap(),
+            parse_years(&text, STARS.as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_structured_errors() {
+        let text = HeaderMap::new();
+        let error = parse_years(&text, b"2024: 2,2\n").unwrap_err();
+        assert_eq!(error.detail.code, "invalid_day_count");
+        assert_eq!(error.detail.year, Some(2024));
+
+        let error = parse_years(&text, b"\n2024 2,2\n").unwrap_err();
+        assert_eq!(error.detail.code, "invalid_line");
+        assert_eq!(error.detail.line, Some(2));
+
+        let json = headers(&[("content-type", "application/json")]);
+        let error = parse_years(&json, br#"{"2024\n2023": [2]}"#).unwrap_err();
+        assert_eq!(error.detail.code, "invalid_year");
+        let error = parse_years(&json, br#"{"2024": [3]}"#).unwrap_err();
+        assert_eq!(error.detail.code, "invalid_day_count");
+        assert_eq!(error.detail.line, None);
+        let error = parse_years(&json, b"[").unwrap_err();
+        assert_eq!(error.detail.code, "invalid_json");
+
+        let form = headers(&[("content-type", "application/x-www-form-urlencoded")]);
+        let error = parse_years(&form, b"").unwrap_err();
+        assert_eq!(error.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
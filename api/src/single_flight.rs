@@ -0,0 +1,97 @@
+//! Deduplication of concurrent work, so a burst of cache misses for the same key only fetches the
+//! file once.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::OnceCell;
+
+/// Runs at most one piece of work per key at a time, sharing its result with everyone that asked
+/// for the same key while it was running
+pub struct SingleFlight<T> {
+    flights: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> Self {
+        Self {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run `work` for the key, or wait for the result of the work already running for it. If the
+    /// caller running the work goes away, one of the waiters takes over.
+    pub async fn run<F, Fut>(&self, key: &str, work: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let flight = self
+            .flights
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+
+        let result = flight.get_or_init(work).await.clone();
+
+        // The flight is done, so later callers start a new one
+        let mut flights = self.flights.lock().unwrap_or_else(PoisonError::into_inner);
+        if flights
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, &flight))
+        {
+            flights.remove(key);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_concurrent_calls_share_a_result() {
+        let flights = Arc::new(SingleFlight::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..5)
+            .map(|_| {
+                let (flights, calls) = (flights.clone(), calls.clone());
+                tokio::spawn(async move {
+                    flights
+                        .run("key", || async {
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            calls.fetch_add(1, Ordering::SeqCst)
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), 0);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Once the flight is done, the next call runs the work again
+        let result = flights
+            .run("key", || async { calls.fetch_add(1, Ordering::SeqCst) })
+            .await;
+        assert_eq!(result, 1);
+        assert!(flights.flights.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_keys_are_independent() {
+        let flights = SingleFlight::new();
+        let first = flights.run("first", || async { 1 });
+        let second = flights.run("second", || async { 2 });
+        assert_eq!(tokio::join!(first, second), (1, 2));
+    }
+}
@@ -0,0 +1,1379 @@
+//! Providers that stars files can be fetched from, each with its own route prefix.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{header::LOCATION, redirect, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::metrics::Metrics;
+use crate::{AppError, AppResult, ErrorKind};
+
+/// A file in a repository on one of the git hosting providers
+#[derive(Debug, Clone)]
+pub struct RepoFile {
+    pub user: String,
+    pub repo: String,
+    pub branch: String,
+    /// Path of the `.txt` stars file within the repository
+    pub path: String,
+}
+
+/// Characters escaped in the segments of upstream URLs, everything but the unreserved ones
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Escape a value to use as a single path segment or query value of an upstream URL
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, SEGMENT).to_string()
+}
+
+/// Escape each segment of a path, keeping the `/` between them
+fn encode_path(path: &str) -> String {
+    path.split('/').map(encode).collect::<Vec<_>>().join("/")
+}
+
+/// `.` and `..` segments are normalised away by URL parsers even when escaped, which would
+/// change which repository or file an upstream URL points at
+fn is_dot_segment(segment: &str) -> bool {
+    matches!(segment, "." | "..")
+}
+
+impl RepoFile {
+    /// The route parameters are already percent-decoded, so reject anything that would escape the
+    /// repository once it's part of an upstream URL
+    pub fn new(user: String, repo: String, branch: String, path: String) -> AppResult<Self> {
+        for (name, value) in [("user", &user), ("repository", &repo)] {
+            if value.is_empty() || value.contains('/') || is_dot_segment(value) {
+                return Err(AppError::ValidationError(
+                    ErrorKind::Path,
+                    format!("Invalid {} name: {}", name, value),
+                ));
+            }
+        }
+        if branch.split('/').any(is_dot_segment) {
+            return Err(AppError::ValidationError(
+                ErrorKind::Path,
+                format!("Invalid branch name: {}", branch),
+            ));
+        }
+        if path.split('/').any(is_dot_segment) {
+            return Err(AppError::ValidationError(
+                ErrorKind::Path,
+                format!("Invalid file path: {}", path),
+            ));
+        }
+
+        Ok(Self {
+            user,
+            repo,
+            branch,
+            path,
+        })
+    }
+}
+
+impl fmt::Display for RepoFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}/{}",
+            self.user, self.repo, self.branch, self.path
+        )
+    }
+}
+
+/// The HTTP client files are fetched with, along with the limit on their size
+#[derive(Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    /// Doesn't follow redirects, for providers that need to check where they lead
+    no_redirects: reqwest::Client,
+    /// Largest file that will be fetched, in bytes
+    pub max_file_size: u64,
+}
+
+impl HttpClient {
+    pub fn new(timeout: Duration, user_agent: &str, max_file_size: u64) -> reqwest::Result<Self> {
+        let builder = || {
+            reqwest::Client::builder()
+                .timeout(timeout)
+                .user_agent(user_agent)
+                .pool_max_idle_per_host(32)
+        };
+
+        Ok(Self {
+            client: builder().build()?,
+            no_redirects: builder().redirect(redirect::Policy::none()).build()?,
+            max_file_size,
+        })
+    }
+
+    pub fn get(&self, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        self.client.get(url)
+    }
+
+    /// A request that returns redirects as they are, instead of following them
+    pub fn get_without_redirects(&self, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        self.no_redirects.get(url)
+    }
+
+    fn check_size(&self, size: u64) -> AppResult<()> {
+        if size > self.max_file_size {
+            Err(AppError::FileTooBig {
+                size,
+                max: self.max_file_size,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A fetched stars file, with what's needed to revalidate it with the upstream later
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetchedFile {
+    pub content: String,
+    /// `ETag` of the upstream response, sent back as `If-None-Match` to revalidate the file
+    pub etag: Option<String>,
+    /// Git blob SHA of the file, for providers that report it
+    pub sha: Option<String>,
+}
+
+/// The result of revalidating a previously fetched file with the upstream
+#[derive(Debug)]
+pub enum Revalidated {
+    Unchanged,
+    Changed(FetchedFile),
+}
+
+/// A source that stars files can be fetched from. Providers and locations are cloned into
+/// background refreshes, so they need to be cheap to clone.
+pub trait SourceProvider: Clone + Send + Sync + 'static {
+    /// Identifies where the file lives within the provider
+    type Location: fmt::Display + Clone + Send + Sync + 'static;
+
+    /// Short name of the provider, used for logs and to namespace cache keys
+    fn name(&self) -> &'static str;
+
+    /// Fetch the raw content of a stars file, failing if it's bigger than the max file size
+    fn fetch_file(
+        &self,
+        client: &HttpClient,
+        location: &Self::Location,
+    ) -> impl Future<Output = AppResult<String>> + Send;
+
+    /// Fetch the file unless it's unchanged since the upstream returned `etag` for it. Providers
+    /// without conditional requests fetch the whole file every time.
+    fn fetch_file_if_changed(
+        &self,
+        client: &HttpClient,
+        location: &Self::Location,
+        _etag: Option<&str>,
+    ) -> impl Future<Output = AppResult<Revalidated>> + Send {
+        async move {
+            let content = self.fetch_file(client, location).await?;
+            Ok(Revalidated::Changed(FetchedFile {
+                content,
+                etag: None,
+                sha: None,
+            }))
+        }
+    }
+}
+
+fn fetch_error(provider: &str, error: impl fmt::Display) -> AppError {
+    AppError::FetchError(format!("{}: {}", provider, error))
+}
+
+/// Map unsuccessful responses to errors, with 404s being reported as the file not being found
+fn check_status(provider: &str, response: &reqwest::Response, location: &str) -> AppResult<()> {
+    match response.status() {
+        StatusCode::NOT_FOUND => Err(AppError::NotFound(
+            ErrorKind::File,
+            format!("File not found: {}", location),
+        )),
+        status if !status.is_success() => Err(fetch_error(
+            provider,
+            format!("Unexpected status {} for {}", status, location),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Read a response body as text, bailing out as soon as it's known to be bigger than the max
+/// file size, rather than trusting the upstream to only serve small files
+async fn read_limited_text(
+    provider: &str,
+    client: &HttpClient,
+    mut response: reqwest::Response,
+) -> AppResult<String> {
+    if let Some(size) = response.content_length() {
+        client.check_size(size)?;
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| fetch_error(provider, format!("Failed to read response: {}", e)))?
+    {
+        body.extend_from_slice(&chunk);
+        client.check_size(body.len() as u64)?;
+    }
+
+    String::from_utf8(body).map_err(|e| {
+        AppError::ValidationError(ErrorKind::File, format!("File is not valid UTF-8: {}", e))
+    })
+}
+
+async fn get_limited_text(provider: &str, client: &HttpClient, url: &str) -> AppResult<String> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| fetch_error(provider, e))?;
+
+    check_status(provider, &response, url)?;
+    read_limited_text(provider, client, response).await
+}
+
+#[derive(Deserialize)]
+struct GitHubFileMetadata {
+    size: u64,
+    sha: Option<String>,
+    download_url: Option<String>,
+    /// The file itself, included for small files
+    content: Option<String>,
+    encoding: Option<String>,
+}
+
+impl GitHubFileMetadata {
+    /// Decode the inline content, if the contents API included it
+    fn inline_content(&self) -> AppResult<Option<String>> {
+        let content = match (&self.content, self.encoding.as_deref()) {
+            (Some(content), Some("base64")) if !content.is_empty() => content,
+            _ => return Ok(None),
+        };
+
+        // GitHub wraps the base64 content in lines
+        let content: String = content.split_whitespace().collect();
+        let bytes = BASE64_STANDARD
+            .decode(content)
+            .map_err(|e| fetch_error("github", format!("Failed to decode content: {}", e)))?;
+
+        String::from_utf8(bytes).map(Some).map_err(|e| {
+            AppError::ValidationError(ErrorKind::File, format!("File is not valid UTF-8: {}", e))
+        })
+    }
+}
+
+/// Files in GitHub repositories, through the contents API
+#[derive(Clone)]
+pub struct GitHub {
+    base_url: String,
+    token: String,
+    /// Tokens for private repositories, by lowercase `owner` or `owner/repo`
+    owner_tokens: Arc<HashMap<String, String>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl GitHub {
+    pub fn new(base_url: &str, token: String) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+            owner_tokens: Arc::default(),
+            metrics: None,
+        }
+    }
+
+    /// Use the tokens for the repositories of an owner, or a single `owner/repo`, instead of the
+    /// server token
+    pub fn with_owner_tokens(mut self, tokens: &BTreeMap<String, String>) -> Self {
+        let tokens = tokens
+            .iter()
+            .map(|(owner, token)| (owner.to_lowercase(), token.clone()))
+            .collect();
+        self.owner_tokens = Arc::new(tokens);
+        self
+    }
+
+    /// Record the latency, errors and rate limit of requests to GitHub
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn has_token(&self) -> bool {
+        !self.token.is_empty()
+    }
+
+    /// How many owners and repositories have their own token
+    pub fn owner_token_count(&self) -> usize {
+        self.owner_tokens.len()
+    }
+
+    /// The token for a repository, preferring one for the repository over one for its owner, and
+    /// falling back to the server token. GitHub names are case insensitive.
+    fn token_for(&self, user: &str, repo: &str) -> &str {
+        let repo_key = format!("{}/{}", user, repo).to_lowercase();
+        self.owner_tokens
+            .get(&repo_key)
+            .or_else(|| self.owner_tokens.get(&user.to_lowercase()))
+            .unwrap_or(&self.token)
+    }
+
+    fn request(&self, client: &HttpClient, url: &str, token: &str) -> reqwest::RequestBuilder {
+        let request = client.get(url);
+
+        if token.is_empty() {
+            request
+        } else {
+            request.header("Authorization", format!("Bearer {}", token))
+        }
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> AppResult<reqwest::Response> {
+        let started = Instant::now();
+        let response = request.send().await;
+        if let Some(metrics) = &self.metrics {
+            let status = response.as_ref().ok().map(|response| response.status());
+            metrics.record_github_request(started, status.map(|status| status.as_u16()));
+        }
+        let response = response.map_err(|mut e| {
+            // Download URLs of private files have a token in the query
+            if let Some(url) = e.url_mut() {
+                url.set_query(None);
+            }
+            fetch_error(self.name(), e)
+        })?;
+
+        if let (Some(remaining), Some(limit)) = (
+            response.headers().get("x-ratelimit-remaining"),
+            response.headers().get("x-ratelimit-limit"),
+        ) {
+            let remaining = remaining.to_str().unwrap_or("unknown");
+            info!(
+                "GitHub API Rate Limit - Remaining: {}, Total: {}",
+                remaining,
+                limit.to_str().unwrap_or("unknown")
+            );
+            if let (Some(metrics), Ok(remaining)) = (&self.metrics, remaining.parse()) {
+                metrics.set_github_rate_limit_remaining(remaining);
+            }
+        }
+
+        Ok(response)
+    }
+
+    /// Fetch the name of the default branch of a repository
+    pub async fn fetch_default_branch(
+        &self,
+        client: &HttpClient,
+        user: &str,
+        repo: &str,
+    ) -> AppResult<String> {
+        let api_url = format!("{}/repos/{}/{}", self.base_url, encode(user), encode(repo));
+
+        let token = self.token_for(user, repo);
+        let response = self.send(self.request(client, &api_url, token)).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(AppError::NotFound(
+                ErrorKind::File,
+                format!("Repository not found: {}/{}", user, repo),
+            ));
+        }
+        check_status(self.name(), &response, &api_url)?;
+
+        let repository: GitHubRepository = response
+            .json()
+            .await
+            .map_err(|e| fetch_error(self.name(), format!("Failed to parse repository: {}", e)))?;
+        Ok(repository.default_branch)
+    }
+
+    /// Fetch the metadata of a file, which is `None` when it's unchanged since `etag`. The
+    /// `ETag` of the response is returned along with the metadata.
+    async fn fetch_metadata(
+        &self,
+        client: &HttpClient,
+        file: &RepoFile,
+        etag: Option<&str>,
+    ) -> AppResult<Option<(GitHubFileMetadata, Option<String>)>> {
+        let api_url = format!(
+            "{}/repos/{}/{}/contents/{}?ref={}",
+            self.base_url,
+            encode(&file.user),
+            encode(&file.repo),
+            encode_path(&file.path),
+            encode(&file.branch)
+        );
+
+        let token = self.token_for(&file.user, &file.repo);
+        let mut request = self.request(client, &api_url, token);
+        if let Some(etag) = etag {
+            request = request.header("If-None-Match", etag);
+        }
+
+        // Conditional requests that return 304 don't count against the rate limit
+        let response = self.send(request).await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        check_status(self.name(), &response, &api_url)?;
+
+        let etag = response
+            .headers()
+            .get("ETag")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let metadata = response
+            .json()
+            .await
+            .map_err(|e| fetch_error(self.name(), format!("Failed to parse metadata: {}", e)))?;
+
+        Ok(Some((metadata, etag)))
+    }
+}
+
+#[derive(Deserialize)]
+struct GitHubRepository {
+    default_branch: String,
+}
+
+impl SourceProvider for GitHub {
+    type Location = RepoFile;
+
+    fn name(&self) -> &'static str {
+        "github"
+    }
+
+    async fn fetch_file(&self, client: &HttpClient, file: &RepoFile) -> AppResult<String> {
+        match self.fetch_file_if_changed(client, file, None).await? {
+            Revalidated::Changed(fetched) => Ok(fetched.content),
+            Revalidated::Unchanged => Err(fetch_error(
+                self.name(),
+                format!("Unexpected 304 for {}", file),
+            )),
+        }
+    }
+
+    async fn fetch_file_if_changed(
+        &self,
+        client: &HttpClient,
+        file: &RepoFile,
+        etag: Option<&str>,
+    ) -> AppResult<Revalidated> {
+        let Some((metadata, etag)) = self.fetch_metadata(client, file, etag).await? else {
+            return Ok(Revalidated::Unchanged);
+        };
+
+        // Check the file size before downloading anything
+        client.check_size(metadata.size)?;
+
+        // Use the content from the metadata when it's there, to save a second request
+        let content = match metadata.inline_content()? {
+            Some(content) => content,
+            None => {
+                let download_url = metadata.download_url.ok_or_else(|| {
+                    fetch_error(
+                        self.name(),
+                        format!("No content or download URL for {}", file),
+                    )
+                })?;
+                let token = self.token_for(&file.user, &file.repo);
+                let response = self
+                    .send(self.request(client, &download_url, token))
+                    .await?;
+                // Download URLs of private files have a token in the query, so leave it out of
+                // the errors
+                let location = download_url.split('?').next().unwrap_or_default();
+                check_status(self.name(), &response, location)?;
+                read_limited_text(self.name(), client, response).await?
+            }
+        };
+
+        Ok(Revalidated::Changed(FetchedFile {
+            content,
+            etag,
+            sha: metadata.sha,
+        }))
+    }
+}
+
+/// A file in a GitHub gist
+#[derive(Debug, Clone)]
+pub struct GistFile {
+    pub user: String,
+    pub gist_id: String,
+    /// Name of the `.txt` stars file within the gist
+    pub file: String,
+}
+
+impl GistFile {
+    /// Like [`RepoFile::new`], reject a user or gist ID that would change the upstream URL
+    pub fn new(user: String, gist_id: String, file: String) -> AppResult<Self> {
+        for (name, value) in [("user name", &user), ("gist id", &gist_id)] {
+            if value.is_empty() || value.contains('/') || is_dot_segment(value) {
+                return Err(AppError::ValidationError(
+                    ErrorKind::Path,
+                    format!("Invalid {}: {}", name, value),
+                ));
+            }
+        }
+
+        Ok(Self {
+            user,
+            gist_id,
+            file,
+        })
+    }
+}
+
+impl fmt::Display for GistFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.user, self.gist_id, self.file)
+    }
+}
+
+#[derive(Deserialize)]
+struct GistMetadata {
+    owner: Option<GistOwner>,
+    files: HashMap<String, GistFileMetadata>,
+}
+
+#[derive(Deserialize)]
+struct GistOwner {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct GistFileMetadata {
+    size: u64,
+    raw_url: String,
+    #[serde(default)]
+    truncated: bool,
+    content: Option<String>,
+}
+
+/// Files in GitHub gists, through the gists API with the server token of the repository source
+#[derive(Clone)]
+pub struct Gist {
+    github: GitHub,
+}
+
+impl Gist {
+    pub fn new(github: GitHub) -> Self {
+        Self { github }
+    }
+}
+
+impl SourceProvider for Gist {
+    type Location = GistFile;
+
+    fn name(&self) -> &'static str {
+        "gist"
+    }
+
+    async fn fetch_file(&self, client: &HttpClient, file: &GistFile) -> AppResult<String> {
+        let api_url = format!("{}/gists/{}", self.github.base_url, encode(&file.gist_id));
+
+        let response = self
+            .github
+            .send(self.github.request(client, &api_url, &self.github.token))
+            .await?;
+        check_status(self.name(), &response, &api_url)?;
+
+        let gist: GistMetadata = response
+            .json()
+            .await
+            .map_err(|e| fetch_error(self.name(), format!("Failed to parse metadata: {}", e)))?;
+
+        // The gist ID alone identifies the gist, so make sure it belongs to the user in the path
+        let owner = gist.owner.map(|owner| owner.login).unwrap_or_default();
+        if !owner.eq_ignore_ascii_case(&file.user) {
+            return Err(AppError::NotFound(
+                ErrorKind::File,
+                format!("Gist {} not found for user {}", file.gist_id, file.user),
+            ));
+        }
+
+        let metadata = gist.files.get(&file.file).ok_or_else(|| {
+            AppError::NotFound(
+                ErrorKind::File,
+                format!("File not found in gist: {}", file.file),
+            )
+        })?;
+
+        client.check_size(metadata.size)?;
+
+        // Small files are included in the gist response, otherwise fall back to the raw URL
+        match &metadata.content {
+            Some(content) if !metadata.truncated => Ok(content.clone()),
+            _ => {
+                let response = self
+                    .github
+                    .send(
+                        self.github
+                            .request(client, &metadata.raw_url, &self.github.token),
+                    )
+                    .await?;
+                check_status(self.name(), &response, &metadata.raw_url)?;
+                read_limited_text(self.name(), client, response).await
+            }
+        }
+    }
+}
+
+/// Files in GitLab repositories, on gitlab.com or a self-managed instance
+#[derive(Clone)]
+pub struct GitLab {
+    base_url: String,
+}
+
+impl GitLab {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl SourceProvider for GitLab {
+    type Location = RepoFile;
+
+    fn name(&self) -> &'static str {
+        "gitlab"
+    }
+
+    async fn fetch_file(&self, client: &HttpClient, file: &RepoFile) -> AppResult<String> {
+        // GitLab wants both the project and the file path URL encoded as a single segment
+        let url = format!(
+            "{}/api/v4/projects/{}%2F{}/repository/files/{}/raw?ref={}",
+            self.base_url,
+            encode(&file.user),
+            encode(&file.repo),
+            encode(&file.path),
+            encode(&file.branch)
+        );
+
+        get_limited_text(self.name(), client, &url).await
+    }
+}
+
+/// Files in Gitea or Forgejo repositories
+#[derive(Clone)]
+pub struct Gitea {
+    base_url: String,
+}
+
+impl Gitea {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl SourceProvider for Gitea {
+    type Location = RepoFile;
+
+    fn name(&self) -> &'static str {
+        "gitea"
+    }
+
+    async fn fetch_file(&self, client: &HttpClient, file: &RepoFile) -> AppResult<String> {
+        let url = format!(
+            "{}/api/v1/repos/{}/{}/raw/{}?ref={}",
+            self.base_url,
+            encode(&file.user),
+            encode(&file.repo),
+            encode_path(&file.path),
+            encode(&file.branch)
+        );
+
+        get_limited_text(self.name(), client, &url).await
+    }
+}
+
+/// Files in Bitbucket Cloud repositories
+#[derive(Clone)]
+pub struct Bitbucket {
+    base_url: String,
+}
+
+impl Bitbucket {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl SourceProvider for Bitbucket {
+    type Location = RepoFile;
+
+    fn name(&self) -> &'static str {
+        "bitbucket"
+    }
+
+    async fn fetch_file(&self, client: &HttpClient, file: &RepoFile) -> AppResult<String> {
+        let url = format!(
+            "{}/2.0/repositories/{}/{}/src/{}/{}",
+            self.base_url,
+            encode(&file.user),
+            encode(&file.repo),
+            encode(&file.branch),
+            encode_path(&file.path)
+        );
+
+        get_limited_text(self.name(), client, &url).await
+    }
+}
+
+/// Redirects followed when fetching a raw URL, each checked against the allowlist
+const MAX_RAW_REDIRECTS: usize = 5;
+
+/// Files at arbitrary URLs, limited to an allowlist of hosts so the API can't be used as an open
+/// proxy. An empty allowlist disables the provider.
+#[derive(Clone)]
+pub struct RawUrl {
+    allowed_hosts: Vec<String>,
+}
+
+impl RawUrl {
+    /// Hosts in the allowlist can either be a plain host, allowing any port, or `host:port`
+    pub fn new(allowed_hosts: Vec<String>) -> Self {
+        Self { allowed_hosts }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.allowed_hosts.is_empty()
+    }
+
+    /// Parse a URL, only accepting http(s) URLs for hosts in the allowlist
+    pub fn parse_url(&self, url: &str) -> AppResult<Url> {
+        let url = Url::parse(url).map_err(|e| {
+            AppError::ValidationError(ErrorKind::Query, format!("Invalid URL {}: {}", url, e))
+        })?;
+
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(AppError::ValidationError(
+                ErrorKind::Query,
+                format!("Unsupported URL scheme: {}", url.scheme()),
+            ));
+        }
+
+        let host = url.host_str().unwrap_or_default();
+        let host_with_port = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        if !self
+            .allowed_hosts
+            .iter()
+            .any(|allowed| allowed == host || *allowed == host_with_port)
+        {
+            return Err(AppError::NotFound(
+                ErrorKind::Query,
+                format!("Host not allowed: {}", host),
+            ));
+        }
+
+        Ok(url)
+    }
+}
+
+impl SourceProvider for RawUrl {
+    type Location = Url;
+
+    fn name(&self) -> &'static str {
+        "raw"
+    }
+
+    /// Redirects are followed by hand, so that an allowed host can't redirect to one that isn't
+    async fn fetch_file(&self, client: &HttpClient, url: &Url) -> AppResult<String> {
+        let mut url = url.clone();
+        for _ in 0..=MAX_RAW_REDIRECTS {
+            let response = client
+                .get_without_redirects(url.clone())
+                .send()
+                .await
+                .map_err(|e| fetch_error(self.name(), e))?;
+
+            if !response.status().is_redirection() {
+                check_status(self.name(), &response, url.as_str())?;
+                return read_limited_text(self.name(), client, response).await;
+            }
+
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| {
+                    fetch_error(
+                        self.name(),
+                        format!("Redirect without a location from {}", url),
+                    )
+                })?;
+            let next = url.join(location).map_err(|e| {
+                fetch_error(self.name(), format!("Invalid redirect from {}: {}", url, e))
+            })?;
+            url = self.parse_url(next.as_str())?;
+        }
+
+        Err(fetch_error(
+            self.name(),
+            format!("Too many redirects fetching {}", url),
+        ))
+    }
+}
+
+/// All the configured providers
+#[derive(Clone)]
+pub struct Sources {
+    pub github: GitHub,
+    pub gist: Gist,
+    pub gitlab: GitLab,
+    pub gitea: Gitea,
+    pub bitbucket: Bitbucket,
+    pub raw: RawUrl,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        http::{HeaderMap, Uri},
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const STARS: &str = "2024: 2,2,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0";
+
+    fn client() -> HttpClient {
+        HttpClient::new(Duration::from_secs(5), "test", 1024).unwrap()
+    }
+
+    /// Start a stub upstream on a random port, serving the given bodies by path and query and
+    /// 404s for everything else. The routes are built from the base URL of the server, and the
+    /// returned counter tracks how many requests the stub has received.
+    async fn stub_server(
+        routes: impl FnOnce(&str) -> Vec<(String, String)>,
+    ) -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let routes = routes(&base_url);
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let counter = requests.clone();
+        let app = Router::new().fallback(move |uri: Uri| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let body = routes
+                .iter()
+                .find(|(path, _)| *path == uri.to_string())
+                .map(|(_, body)| body.clone());
+            async move {
+                match body {
+                    Some(body) => (StatusCode::OK, body),
+                    None => (StatusCode::NOT_FOUND, String::new()),
+                }
+            }
+        });
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        (base_url, requests)
+    }
+
+    fn repo_file(path: &str) -> RepoFile {
+        RepoFile::new(
+            "user".to_string(),
+            "repo".to_string(),
+            "main".to_string(),
+            path.to_string(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_github_fetch() {
+        let (base_url, requests) = stub_server(|base_url| {
+            vec![
+                (
+                    "/repos/user/repo/contents/stars.txt?ref=main".to_string(),
+                    format!(
+                        r#"{{"size": {}, "download_url": "{}/raw/stars.txt"}}"#,
+                        STARS.len(),
+                        base_url
+                    ),
+                ),
+                (
+                    "/repos/user/repo/contents/big.txt?ref=main".to_string(),
+                    format!(
+                        r#"{{"size": 4096, "download_url": "{}/raw/big.txt"}}"#,
+                        base_url
+                    ),
+                ),
+                ("/raw/stars.txt".to_string(), STARS.to_string()),
+            ]
+        })
+        .await;
+        let client = client();
+        let github = GitHub::new(&base_url, String::new());
+
+        let content = github.fetch_file(&client, &repo_file("stars.txt")).await;
+        assert_eq!(content.unwrap(), STARS);
+        // Without inline content the file is downloaded separately
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(matches!(
+            github.fetch_file(&client, &repo_file("big.txt")).await,
+            Err(AppError::FileTooBig { size: 4096, .. })
+        ));
+        assert!(matches!(
+            github.fetch_file(&client, &repo_file("missing.txt")).await,
+            Err(AppError::NotFound(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_github_inline_content() {
+        let encoded = BASE64_STANDARD.encode(STARS);
+        // GitHub splits the base64 content over multiple lines
+        let (first, second) = encoded.split_at(20);
+        let (base_url, requests) = stub_server(|base_url| {
+            vec![
+                (
+                    "/repos/user/repo/contents/stars.txt?ref=main".to_string(),
+                    format!(
+                        r#"{{"size": {}, "download_url": "{}/raw/stars.txt", "encoding": "base64", "content": "{}\n{}\n"}}"#,
+                        STARS.len(),
+                        base_url,
+                        first,
+                        second
+                    ),
+                ),
+                ("/raw/stars.txt".to_string(), STARS.to_string()),
+            ]
+        })
+        .await;
+        let client = client();
+        let github = GitHub::new(&base_url, String::new());
+
+        let content = github.fetch_file(&client, &repo_file("stars.txt")).await;
+        assert_eq!(content.unwrap(), STARS);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_github_default_branch() {
+        let (base_url, _) = stub_server(|_| {
+            vec![
+                (
+                    "/repos/user/repo".to_string(),
+                    r#"{"name": "repo", "default_branch": "trunk"}"#.to_string(),
+                ),
+                ("/repos/user/broken".to_string(), "{}".to_string()),
+            ]
+        })
+        .await;
+        let client = client();
+        let github = GitHub::new(&base_url, String::new());
+
+        let branch = github.fetch_default_branch(&client, "user", "repo").await;
+        assert_eq!(branch.unwrap(), "trunk");
+        assert!(matches!(
+            github
+                .fetch_default_branch(&client, "user", "missing")
+                .await,
+            Err(AppError::NotFound(..))
+        ));
+        assert!(matches!(
+            github.fetch_default_branch(&client, "user", "broken").await,
+            Err(AppError::FetchError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_github_owner_tokens() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        // Echo the token back as the default branch
+        let app = Router::new().route(
+            "/repos/:user/:repo",
+            get(|headers: HeaderMap| async move {
+                let token = headers
+                    .get("Authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .unwrap_or("none");
+                format!(r#"{{"default_branch": "{}"}}"#, token)
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = client();
+        let tokens = BTreeMap::from([
+            ("Private".to_string(), "owner-token".to_string()),
+            ("private/stars".to_string(), "repo-token".to_string()),
+        ]);
+        let github = GitHub::new(&base_url, "server-token".to_string()).with_owner_tokens(&tokens);
+
+        let token = |user: &'static str, repo: &'static str| {
+            let github = github.clone();
+            let client = client.clone();
+            async move {
+                github
+                    .fetch_default_branch(&client, user, repo)
+                    .await
+                    .unwrap()
+            }
+        };
+        assert_eq!(token("private", "other").await, "owner-token");
+        assert_eq!(token("PRIVATE", "Stars").await, "repo-token");
+        assert_eq!(token("public", "stars").await, "server-token");
+    }
+
+    #[tokio::test]
+    async fn test_github_revalidation() {
+        const ETAG: &str = "\"abc123\"";
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().route(
+            "/repos/user/repo/contents/stars.txt",
+            get(|headers: HeaderMap| async move {
+                if headers
+                    .get("If-None-Match")
+                    .is_some_and(|etag| etag == ETAG)
+                {
+                    return StatusCode::NOT_MODIFIED.into_response();
+                }
+                let metadata = format!(
+                    r#"{{"size": {}, "sha": "f00", "encoding": "base64", "content": "{}"}}"#,
+                    STARS.len(),
+                    BASE64_STANDARD.encode(STARS)
+                );
+                ([("ETag", ETAG)], metadata).into_response()
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = client();
+        let github = GitHub::new(&base_url, String::new());
+        let file = repo_file("stars.txt");
+
+        let Revalidated::Changed(fetched) = github
+            .fetch_file_if_changed(&client, &file, None)
+            .await
+            .unwrap()
+        else {
+            panic!("Expected the file to be fetched");
+        };
+        assert_eq!(fetched.content, STARS);
+        assert_eq!(fetched.etag.as_deref(), Some(ETAG));
+        assert_eq!(fetched.sha.as_deref(), Some("f00"));
+
+        assert!(matches!(
+            github
+                .fetch_file_if_changed(&client, &file, Some(ETAG))
+                .await,
+            Ok(Revalidated::Unchanged)
+        ));
+        assert!(matches!(
+            github
+                .fetch_file_if_changed(&client, &file, Some("\"stale\""))
+                .await,
+            Ok(Revalidated::Changed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_gist_fetch() {
+        let (base_url, _) = stub_server(|base_url| {
+            vec![
+                (
+                    "/gists/abc123".to_string(),
+                    format!(
+                        r#"{{"owner": {{"login": "User"}}, "files": {{
+                            "stars.txt": {{"size": {}, "raw_url": "{}/raw", "content": "{}"}},
+                            "big.txt": {{"size": 4096, "raw_url": "{}/raw/big.txt"}}
+                        }}}}"#,
+                        STARS.len(),
+                        base_url,
+                        STARS,
+                        base_url
+                    ),
+                ),
+                (
+                    "/gists/truncated".to_string(),
+                    format!(
+                        r#"{{"owner": {{"login": "user"}}, "files": {{
+                            "stars.txt": {{"size": {}, "raw_url": "{}/raw/stars.txt", "truncated": true, "content": ""}}
+                        }}}}"#,
+                        STARS.len(),
+                        base_url
+                    ),
+                ),
+                ("/raw/stars.txt".to_string(), STARS.to_string()),
+            ]
+        })
+        .await;
+        let client = client();
+        let gist = Gist::new(GitHub::new(&base_url, String::new()));
+        let gist_file = |user: &str, gist_id: &str, file: &str| GistFile {
+            user: user.to_string(),
+            gist_id: gist_id.to_string(),
+            file: file.to_string(),
+        };
+
+        let inline = gist_file("user", "abc123", "stars.txt");
+        assert_eq!(gist.fetch_file(&client, &inline).await.unwrap(), STARS);
+        let truncated = gist_file("user", "truncated", "stars.txt");
+        assert_eq!(gist.fetch_file(&client, &truncated).await.unwrap(), STARS);
+
+        assert!(matches!(
+            gist.fetch_file(&client, &gist_file("user", "abc123", "big.txt"))
+                .await,
+            Err(AppError::FileTooBig { size: 4096, .. })
+        ));
+        assert!(matches!(
+            gist.fetch_file(&client, &gist_file("someone", "abc123", "stars.txt"))
+                .await,
+            Err(AppError::NotFound(..))
+        ));
+        assert!(matches!(
+            gist.fetch_file(&client, &gist_file("user", "abc123", "other.txt"))
+                .await,
+            Err(AppError::NotFound(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_repo_providers_fetch() {
+        let big = "0".repeat(client().max_file_size as usize + 1);
+        let (base_url, _) =
+            stub_server(|_| {
+                vec![
+                (
+                    "/api/v4/projects/user%2Frepo/repository/files/src%2Fstars.txt/raw?ref=main"
+                        .to_string(),
+                    STARS.to_string(),
+                ),
+                (
+                    "/api/v1/repos/user/repo/raw/src/stars.txt?ref=main".to_string(),
+                    STARS.to_string(),
+                ),
+                (
+                    "/2.0/repositories/user/repo/src/main/src/stars.txt".to_string(),
+                    STARS.to_string(),
+                ),
+                ("/2.0/repositories/user/repo/src/main/big.txt".to_string(), big),
+            ]
+            })
+            .await;
+        let client = client();
+        let file = repo_file("src/stars.txt");
+
+        let gitlab = GitLab::new(&base_url);
+        assert_eq!(gitlab.fetch_file(&client, &file).await.unwrap(), STARS);
+        let gitea = Gitea::new(&format!("{}/", base_url));
+        assert_eq!(gitea.fetch_file(&client, &file).await.unwrap(), STARS);
+        let bitbucket = Bitbucket::new(&base_url);
+        assert_eq!(bitbucket.fetch_file(&client, &file).await.unwrap(), STARS);
+
+        assert!(matches!(
+            bitbucket.fetch_file(&client, &repo_file("big.txt")).await,
+            Err(AppError::FileTooBig { .. })
+        ));
+        assert!(matches!(
+            gitea.fetch_file(&client, &repo_file("missing.txt")).await,
+            Err(AppError::NotFound(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_raw_url_fetch() {
+        let (base_url, _) =
+            stub_server(|_| vec![("/stars.txt".to_string(), STARS.to_string())]).await;
+        let host = base_url.trim_start_matches("http://").to_string();
+        let raw = RawUrl::new(vec![host]);
+        let client = client();
+
+        let url = raw.parse_url(&format!("{}/stars.txt", base_url)).unwrap();
+        assert_eq!(raw.fetch_file(&client, &url).await.unwrap(), STARS);
+    }
+
+    #[tokio::test]
+    async fn test_raw_url_redirects() {
+        let (outside_url, outside_requests) =
+            stub_server(|_| vec![("/secret".to_string(), STARS.to_string())]).await;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/stars.txt", get(|| async { STARS }))
+            .route(
+                "/moved.txt",
+                get(|| async { axum::response::Redirect::temporary("/stars.txt") }),
+            )
+            .route(
+                "/outside.txt",
+                get(move || async move {
+                    axum::response::Redirect::temporary(&format!("{}/secret", outside_url))
+                }),
+            )
+            .route(
+                "/loop.txt",
+                get(|| async { axum::response::Redirect::temporary("/loop.txt") }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let host = base_url.trim_start_matches("http://").to_string();
+        let raw = RawUrl::new(vec![host]);
+        let client = client();
+        let fetch = |path: &str| {
+            let url = raw.parse_url(&format!("{}{}", base_url, path)).unwrap();
+            let (raw, client) = (raw.clone(), client.clone());
+            async move { raw.fetch_file(&client, &url).await }
+        };
+
+        // Redirects within the allowlist are followed
+        assert_eq!(fetch("/moved.txt").await.unwrap(), STARS);
+        // The other stub is on the same IP, but a port that isn't allowed
+        assert!(matches!(
+            fetch("/outside.txt").await,
+            Err(AppError::NotFound(_, message)) if message.starts_with("Host not allowed")
+        ));
+        assert_eq!(outside_requests.load(Ordering::SeqCst), 0);
+        assert!(matches!(
+            fetch("/loop.txt").await,
+            Err(AppError::FetchError(message)) if message.contains("Too many redirects")
+        ));
+    }
+
+    #[test]
+    fn test_raw_url_allowlist() {
+        let raw = RawUrl::new(vec![
+            "example.com".to_string(),
+            "localhost:8080".to_string(),
+        ]);
+
+        assert!(raw.parse_url("https://example.com/stars.txt").is_ok());
+        assert!(raw.parse_url("http://example.com:9000/stars.txt").is_ok());
+        assert!(raw.parse_url("http://localhost:8080/stars.txt").is_ok());
+        assert!(matches!(
+            raw.parse_url("http://localhost:9000/stars.txt"),
+            Err(AppError::NotFound(..))
+        ));
+        assert!(matches!(
+            raw.parse_url("https://evil.example.org/stars.txt"),
+            Err(AppError::NotFound(..))
+        ));
+        assert!(matches!(
+            raw.parse_url("file:///etc/passwd"),
+            Err(AppError::ValidationError(..))
+        ));
+        assert!(matches!(
+            raw.parse_url("not a url"),
+            Err(AppError::ValidationError(..))
+        ));
+    }
+
+    #[test]
+    fn test_repo_file_validation() {
+        let new = |user: &str, repo: &str, branch: &str, path: &str| {
+            RepoFile::new(
+                user.to_string(),
+                repo.to_string(),
+                branch.to_string(),
+                path.to_string(),
+            )
+        };
+
+        assert!(new("user", "repo", "feature/x", "src/stars.txt").is_ok());
+        assert!(new("user", "repo.github.io", "main", ".stars.txt").is_ok());
+        for (user, repo, branch, path) in [
+            ("user", "..", "main", "stars.txt"),
+            ("user", "repo/../other", "main", "stars.txt"),
+            ("..", "repo", "main", "stars.txt"),
+            ("", "repo", "main", "stars.txt"),
+            ("user", "repo", "main/../x", "stars.txt"),
+            ("user", "repo", "main", "../../secret/contents/x.txt"),
+            ("user", "repo", "main", "src/./stars.txt"),
+        ] {
+            assert!(
+                matches!(
+                    new(user, repo, branch, path),
+                    Err(AppError::ValidationError(..))
+                ),
+                "{}/{}/{}/{} should be rejected",
+                user,
+                repo,
+                branch,
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_urls_are_escaped() {
+        let (base_url, _) = stub_server(|_| {
+            vec![
+                (
+                    "/repos/user/repo/contents/my%20stars%3F.txt?ref=feature%2Fx%26ref%3Dmain"
+                        .to_string(),
+                    format!(
+                        r#"{{"size": {}, "encoding": "base64", "content": "{}"}}"#,
+                        STARS.len(),
+                        BASE64_STANDARD.encode(STARS)
+                    ),
+                ),
+                (
+                    "/api/v4/projects/user%2Frepo/repository/files/src%2Fstars.txt/raw?ref=a%26b"
+                        .to_string(),
+                    STARS.to_string(),
+                ),
+            ]
+        })
+        .await;
+        let client = client();
+        let file = |branch: &str, path: &str| {
+            RepoFile::new(
+                "user".to_string(),
+                "repo".to_string(),
+                branch.to_string(),
+                path.to_string(),
+            )
+            .unwrap()
+        };
+
+        let github = GitHub::new(&base_url, String::new());
+        let content = github
+            .fetch_file(&client, &file("feature/x&ref=main", "my stars?.txt"))
+            .await;
+        assert_eq!(content.unwrap(), STARS);
+
+        let gitlab = GitLab::new(&base_url);
+        let content = gitlab
+            .fetch_file(&client, &file("a&b", "src/stars.txt"))
+            .await;
+        assert_eq!(content.unwrap(), STARS);
+    }
+
+    #[test]
+    fn test_repo_file_display() {
+        let file = repo_file("src/stars.txt");
+        assert_eq!(file.to_string(), "user/repo/main/src/stars.txt");
+    }
+}
@@ -0,0 +1,325 @@
+//! GitHub push webhooks, invalidating cached responses as soon as a stars file changes rather
+//! than waiting for them to expire.
+
+use std::collections::HashSet;
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::{info, warn};
+
+use crate::sources::SourceProvider;
+use crate::{invalidate_matching, AppError, AppState, ErrorKind};
+
+/// GitHub only lists this many commits in a push event, any further commits are left out
+const MAX_PAYLOAD_COMMITS: usize = 20;
+
+/// Check the `X-Hub-Signature-256` header, the HMAC of the body with the webhook secret
+fn verify_signature(secret: &str, body: &[u8], signature: Option<&str>) -> bool {
+    let Some(signature) = signature
+        .and_then(|signature| signature.strip_prefix("sha256="))
+        .and_then(|signature| hex::decode(signature).ok())
+    else {
+        return false;
+    };
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(body);
+    // Compares in constant time
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    full_name: String,
+}
+
+#[derive(Deserialize)]
+struct Commit {
+    #[serde(default)]
+    added: Vec<String>,
+    #[serde(default)]
+    modified: Vec<String>,
+    #[serde(default)]
+    removed: Vec<String>,
+}
+
+/// The parts of a push event payload needed to tell which files changed
+#[derive(Deserialize)]
+struct PushEvent {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    repository: Repository,
+    #[serde(default)]
+    commits: Vec<Commit>,
+}
+
+impl PushEvent {
+    fn changed_paths(&self) -> HashSet<&str> {
+        self.commits
+            .iter()
+            .flat_map(|commit| [&commit.added, &commit.modified, &commit.removed])
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Whether the push has more commits than the payload lists, so the changed files aren't all
+    /// known
+    fn is_truncated(&self) -> bool {
+        self.commits.len() >= MAX_PAYLOAD_COMMITS
+    }
+
+    /// Whether a cache key of the provider is for a file changed by the push. Keys are
+    /// `<provider>:<user>/<repo>/<branch>/<path>`, optionally followed by `#<variant>`. When the
+    /// payload is truncated, every file of the branch is affected.
+    fn affects(&self, provider: &str, changed_paths: &HashSet<&str>, cache_key: &str) -> bool {
+        let Some(branch) = self.git_ref.strip_prefix("refs/heads/") else {
+            // Tags can't be used in the routes
+            return false;
+        };
+        let Some(file) = cache_key
+            .strip_prefix(provider)
+            .and_then(|key| key.strip_prefix(':'))
+        else {
+            return false;
+        };
+        let file = file.split_once('#').map_or(file, |(file, _)| file);
+
+        // Branches can contain `/`, so the key is matched against the whole prefix rather than
+        // split into its parts
+        let full_name = &self.repository.full_name;
+        let branch_prefix = format!("/{}/", branch);
+        let Some(key_repo) = file.get(..full_name.len()) else {
+            return false;
+        };
+        let Some(path) = file[full_name.len()..].strip_prefix(branch_prefix.as_str()) else {
+            return false;
+        };
+
+        // GitHub names are case insensitive, so the routes may use any case
+        key_repo.eq_ignore_ascii_case(full_name)
+            && (self.is_truncated() || changed_paths.contains(path))
+    }
+}
+
+pub async fn handle_github_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some(secret) = &state.github_webhook_secret else {
+        return AppError::NotFound(ErrorKind::Path, "Webhooks are not enabled".to_string())
+            .into_response();
+    };
+
+    let signature = headers
+        .get("X-Hub-Signature-256")
+        .and_then(|value| value.to_str().ok());
+    if !verify_signature(secret, &body, signature) {
+        warn!("Webhook with an invalid signature");
+        return (StatusCode::UNAUTHORIZED, "Invalid signature").into_response();
+    }
+
+    match headers
+        .get("X-GitHub-Event")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some("push") => {}
+        Some("ping") => return (StatusCode::OK, "pong").into_response(),
+        event => {
+            let event = event.unwrap_or("unknown");
+            return (StatusCode::ACCEPTED, format!("Ignoring {} event", event)).into_response();
+        }
+    }
+
+    let event: PushEvent = match serde_json::from_slice(&body) {
+        Ok(event) => event,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid push event: {}", e),
+            )
+                .into_response()
+        }
+    };
+
+    let provider = state.sources.github.name();
+    let changed_paths = event.changed_paths();
+    let affects = |key: &str| event.affects(provider, &changed_paths, key);
+    let invalidated = invalidate_matching(&*state.cache, affects).await
+        + invalidate_matching(&*state.error_cache, affects).await;
+    // The fetched file would be revalidated anyway, but there's no point in keeping it around
+    invalidate_matching(&*state.file_cache, affects).await;
+
+    info!(
+        repository = %event.repository.full_name,
+        git_ref = %event.git_ref,
+        truncated = event.is_truncated(),
+        invalidated = invalidated,
+        "Push webhook received"
+    );
+
+    (
+        StatusCode::OK,
+        format!("Invalidated {} cache entries", invalidated),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A push event recorded from GitHub, trimmed down to the interesting parts
+    const PUSH_EVENT: &str = r#"{
+        "ref": "refs/heads/main",
+        "before": "6113728f27ae82c7b1a177c8d03f9e96e0adf246",
+        "after": "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c",
+        "repository": {
+            "id": 186853002,
+            "name": "Advent-Of-Code",
+            "full_name": "Ikornaselur/Advent-Of-Code",
+            "private": false,
+            "owner": {"name": "Ikornaselur", "login": "Ikornaselur"},
+            "default_branch": "main"
+        },
+        "pusher": {"name": "ikornaselur"},
+        "commits": [
+            {
+                "id": "3f1ccfa2e6f5b0e4e2fd4ff8b36e4c17ab3d1a05",
+                "message": "Day 3",
+                "added": ["2024/day03.py"],
+                "removed": [],
+                "modified": ["README.md"]
+            },
+            {
+                "id": "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c",
+                "message": "Update stars",
+                "added": [],
+                "removed": [],
+                "modified": [".stars.txt"]
+            }
+        ],
+        "head_commit": {"id": "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c"}
+    }"#;
+
+    fn sign(secret: &str, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let signature = sign("secret", PUSH_EVENT);
+        assert!(verify_signature(
+            "secret",
+            PUSH_EVENT.as_bytes(),
+            Some(&signature)
+        ));
+
+        assert!(!verify_signature(
+            "other",
+            PUSH_EVENT.as_bytes(),
+            Some(&signature)
+        ));
+        assert!(!verify_signature("secret", b"{}", Some(&signature)));
+        assert!(!verify_signature("secret", PUSH_EVENT.as_bytes(), None));
+        assert!(!verify_signature(
+            "secret",
+            PUSH_EVENT.as_bytes(),
+            Some(signature.trim_start_matches("sha256="))
+        ));
+        assert!(!verify_signature(
+            "secret",
+            PUSH_EVENT.as_bytes(),
+            Some("sha256=not-hex")
+        ));
+    }
+
+    #[test]
+    fn test_affected_keys() {
+        let event: PushEvent = serde_json::from_str(PUSH_EVENT).unwrap();
+        let changed_paths = event.changed_paths();
+        let affects = |key: &str| event.affects("github", &changed_paths, key);
+
+        assert!(affects("github:ikornaselur/advent-of-code/main/.stars.txt"));
+        assert!(affects(
+            "github:Ikornaselur/Advent-Of-Code/main/.stars.txt#badge"
+        ));
+        assert!(affects(
+            "github:Ikornaselur/Advent-Of-Code/main/README.md#cell=24"
+        ));
+
+        // Other files, branches, repositories and providers
+        assert!(!affects("github:Ikornaselur/Advent-Of-Code/main/stars.txt"));
+        assert!(!affects("github:Ikornaselur/Advent-Of-Code/dev/.stars.txt"));
+        assert!(!affects("github:Ikornaselur/advent-stars/main/.stars.txt"));
+        assert!(!affects(
+            "gitlab:Ikornaselur/Advent-Of-Code/main/.stars.txt"
+        ));
+        assert!(!affects("gist:Ikornaselur/abc/.stars.txt"));
+    }
+
+    #[test]
+    fn test_branch_with_slashes() {
+        let event: PushEvent =
+            serde_json::from_str(&PUSH_EVENT.replace("refs/heads/main", "refs/heads/feature/aoc"))
+                .unwrap();
+        let changed_paths = event.changed_paths();
+        let affects = |key: &str| event.affects("github", &changed_paths, key);
+
+        assert!(affects(
+            "github:Ikornaselur/Advent-Of-Code/feature/aoc/.stars.txt"
+        ));
+        assert!(!affects(
+            "github:Ikornaselur/Advent-Of-Code/feature/.stars.txt"
+        ));
+        assert!(!affects(
+            "github:Ikornaselur/Advent-Of-Code/feature/aoc/other/.stars.txt"
+        ));
+        assert!(!affects(
+            "github:Ikornaselur/Advent-Of-Code/main/.stars.txt"
+        ));
+    }
+
+    #[test]
+    fn test_truncated_payload() {
+        let mut event: serde_json::Value = serde_json::from_str(PUSH_EVENT).unwrap();
+        let commit = event["commits"][0].clone();
+        event["commits"] = serde_json::Value::Array(vec![commit; MAX_PAYLOAD_COMMITS]);
+        let event: PushEvent = serde_json::from_value(event).unwrap();
+        let changed_paths = event.changed_paths();
+        let affects = |key: &str| event.affects("github", &changed_paths, key);
+
+        // The stars file isn't listed, but may be in one of the commits left out
+        assert!(event.is_truncated());
+        assert!(affects("github:Ikornaselur/Advent-Of-Code/main/.stars.txt"));
+        assert!(affects(
+            "github:ikornaselur/advent-of-code/main/src/stars.txt#badge"
+        ));
+        assert!(!affects("github:Ikornaselur/Advent-Of-Code/dev/.stars.txt"));
+        assert!(!affects("github:Ikornaselur/advent-stars/main/.stars.txt"));
+        assert!(!affects("github:Ikornaselur/Advent-Of-Code"));
+    }
+
+    #[test]
+    fn test_tags_ignored() {
+        let event: PushEvent =
+            serde_json::from_str(&PUSH_EVENT.replace("refs/heads/main", "refs/tags/main")).unwrap();
+        let changed_paths = event.changed_paths();
+        assert!(!event.affects(
+            "github",
+            &changed_paths,
+            "github:Ikornaselur/Advent-Of-Code/main/.stars.txt"
+        ));
+    }
+}
@@ -0,0 +1,272 @@
+//! End to end tests of the API, served on a local port in front of a mock GitHub.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use api::{build_router, config::Config};
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+const STARS: &str = "2024: 2,2,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0";
+
+/// A mock of the GitHub contents API, counting the requests for each file
+struct MockGitHub {
+    base_url: String,
+    requests: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// A valid stars file over the default size limit, with a line for each year
+fn big_file() -> String {
+    (2000..2020)
+        .map(|year| STARS.replace("2024", &year.to_string()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The contents API response for a file with inline content
+fn file_metadata(content: &str) -> Response {
+    format!(
+        r#"{{"size": {}, "encoding": "base64", "content": "{}"}}"#,
+        content.len(),
+        BASE64_STANDARD.encode(content)
+    )
+    .into_response()
+}
+
+impl MockGitHub {
+    /// Serve `stars.txt`, a `big.txt` over the default size limit, an `invalid.txt` that fails
+    /// validation and a `broken.txt` that always fails. Everything else is a 404.
+    async fn start() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(HashMap::new()));
+
+        let counter = requests.clone();
+        let app = Router::new().route(
+            "/repos/user/repo/contents/:file",
+            get(move |Path(file): Path<String>| {
+                *counter.lock().unwrap().entry(file.clone()).or_insert(0) += 1;
+                async move {
+                    match file.as_str() {
+                        "stars.txt" => file_metadata(STARS),
+                        "big.txt" => file_metadata(&big_file()),
+                        "invalid.txt" => file_metadata("2024: 2,2,3"),
+                        "broken.txt" => StatusCode::BAD_GATEWAY.into_response(),
+                        _ => StatusCode::NOT_FOUND.into_response(),
+                    }
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        Self { base_url, requests }
+    }
+
+    fn requests(&self, file: &str) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .get(file)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn config(&self) -> Config {
+        Config {
+            port: 0,
+            github_api_url: self.base_url.clone(),
+            ..Config::default()
+        }
+    }
+}
+
+/// The API, served on a local port
+struct TestApi {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl TestApi {
+    async fn start(config: &Config) -> Self {
+        let app = build_router(config).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+
+        Self {
+            base_url,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Get the path, returning the status, `X-Cache` header and body of the response
+    async fn get(&self, path: &str) -> (StatusCode, String, String) {
+        let response = self
+            .client
+            .get(format!("{}{}", self.base_url, path))
+            .send()
+            .await
+            .unwrap();
+        let status = StatusCode::from_u16(response.status().as_u16()).unwrap();
+        let x_cache = response
+            .headers()
+            .get("X-Cache")
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        (status, x_cache, response.text().await.unwrap())
+    }
+}
+
+#[tokio::test]
+async fn test_cache_hits() {
+    let github = MockGitHub::start().await;
+    let api = TestApi::start(&github.config()).await;
+
+    let (status, x_cache, body) = api.get("/stars/user/repo/main/stars.svg").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(x_cache, "MISS");
+    assert!(body.starts_with("<svg"), "{}", body);
+
+    let (status, x_cache, cached) = api.get("/stars/user/repo/main/stars.svg").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(x_cache, "HIT");
+    assert_eq!(cached, body);
+    assert_eq!(github.requests("stars.txt"), 1);
+}
+
+#[tokio::test]
+async fn test_errors_are_cached() {
+    let github = MockGitHub::start().await;
+    let api = TestApi::start(&github.config()).await;
+
+    for _ in 0..3 {
+        let (status, _, body) = api.get("/stars/user/repo/main/broken.svg").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.contains("Unexpected status 502"), "{}", body);
+    }
+    assert_eq!(github.requests("broken.txt"), 1);
+
+    // The error is cached for the file, whatever it's rendered as
+    let (status, _, _) = api.get("/badge/user/repo/main/broken.svg").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(github.requests("broken.txt"), 1);
+}
+
+#[tokio::test]
+async fn test_oversize_files() {
+    let github = MockGitHub::start().await;
+    let api = TestApi::start(&github.config()).await;
+
+    let (status, _, body) = api.get("/stars/user/repo/main/big.svg").await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(
+        body.contains("exceeds maximum allowed size of 1024 bytes"),
+        "{}",
+        body
+    );
+
+    // The limit comes from the config
+    let config = Config {
+        max_file_size: 4096,
+        ..github.config()
+    };
+    let api = TestApi::start(&config).await;
+    let (status, _, _) = api.get("/stars/user/repo/main/big.svg").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_not_found() {
+    let github = MockGitHub::start().await;
+    let api = TestApi::start(&github.config()).await;
+
+    let (status, _, body) = api.get("/stars/user/repo/main/missing.svg").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(body.contains("File not found"), "{}", body);
+
+    let (status, _, _) = api.get("/stars/user/repo/main/missing.svg").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(github.requests("missing.txt"), 1);
+
+    // Routes that don't exist at all
+    let (status, _, _) = api.get("/stars/user/repo").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_rate_limiting() {
+    let github = MockGitHub::start().await;
+    let config = Config {
+        rate_limit_max_requests: 2,
+        ..github.config()
+    };
+    let api = TestApi::start(&config).await;
+
+    // Each cell size is cached apart, so every request goes upstream
+    for cell in [20, 24] {
+        let path = format!("/stars/user/repo/main/stars.svg?cell={}", cell);
+        let (status, _, _) = api.get(&path).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, _, body) = api.get("/stars/user/repo/main/stars.svg?cell=28").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body, "Rate limit exceeded");
+    assert_eq!(github.requests("stars.txt"), 2);
+
+    // Cached responses are still served
+    let (status, x_cache, _) = api.get("/stars/user/repo/main/stars.svg?cell=20").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(x_cache, "HIT");
+
+    // The limit is per file
+    let (status, _, _) = api.get("/badge/user/repo/main/missing.svg").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_validation_failures() {
+    let github = MockGitHub::start().await;
+    let api = TestApi::start(&github.config()).await;
+
+    let (status, _, body) = api.get("/stars/user/repo/main/invalid.svg").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("2024"), "{}", body);
+
+    let (status, _, _) = api.get("/data/user/repo/main/invalid.json").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(github.requests("invalid.txt"), 1);
+
+    // Invalid query parameters are rejected before anything is fetched
+    let (status, _, body) = api.get("/stars/user/repo/main/stars.svg?theme=nope").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("`theme`"), "{}", body);
+    let (status, _, _) = api.get("/stars/user/repo/main/stars.svg?unknown=1").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Paths that would point the upstream request at another repository or gist
+    for path in [
+        "/stars/user/repo/main/%2E%2E%2F%2E%2E%2Fsecret/contents/x.svg",
+        "/stars/user/repo%2F..%2Fother/main/stars.svg",
+        "/gitea/user/%2E%2E%2Fother/main/stars.svg",
+        "/gist/user/%2E%2E%2Fother/stars.svg",
+    ] {
+        let (status, _, body) = api.get(path).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+        assert!(body.starts_with("Invalid"), "{}", body);
+    }
+    assert_eq!(github.requests("stars.txt"), 0);
+}
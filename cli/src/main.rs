@@ -1,12 +1,16 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::fs;
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use svg::ConflictPolicy;
 
 /// CLI tool to generate SVG visualizations from Advent of Code stars data
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input file containing advent calendar data
     ///
     /// Input file should be a text file with a list of years and the 25 days for that year. The
@@ -17,38 +21,201 @@ struct Args {
     ///
     /// 2024: 2,2,2,2,2,2,2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0
     #[arg(help = "Path to the input .txt file")]
-    input: PathBuf,
+    input: Option<PathBuf>,
 
     /// Optional output file for the SVG (defaults to stdout if not provided)
     #[arg(short, long, help = "Optional path for the output SVG file")]
     output: Option<PathBuf>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show which days gained or lost stars between two stars files
+    Diff {
+        /// The original stars file
+        old: PathBuf,
 
-    let content =
-        fs::read_to_string(&args.input).map_err(|e| format!("Failed to read input file: {}", e))?;
+        /// The updated stars file
+        new: PathBuf,
+
+        /// Optional path for an SVG rendering of the new file with the changes highlighted
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Combine several stars files by year into a single stars file
+    Merge {
+        /// The stars files to merge, conflicts are resolved from left to right
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
 
-    let years = svg::validate_input(&content).map_err(|e| format!("Validation error: {:?}", e))?;
+        /// How to resolve days that have different star values
+        #[arg(short, long, value_enum, default_value_t = ConflictPolicy::Max)]
+        policy: ConflictPolicy,
 
-    let svg_content = svg::generate_svg(years);
+        /// Optional path for the merged stars file (defaults to stdout if not provided)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Chart the cumulative stars over time from private leaderboard JSON exports
+    Timeline {
+        /// Leaderboard JSON files, one per year, as returned by the private leaderboard API
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Member id or name to chart, only needed when a leaderboard has multiple members
+        #[arg(short, long)]
+        member: Option<String>,
+
+        /// Optional path for the output SVG file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Generate a small shields.io style badge with the total stars, or the stars for a year
+    Badge {
+        /// Path to the input .txt file
+        input: PathBuf,
+
+        /// Show the stars for a single year instead of the total
+        #[arg(short, long)]
+        year: Option<usize>,
+
+        /// Optional path for the output SVG file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
 
-    match args.output {
+fn read_years(path: &Path) -> Result<svg::Years, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read input file: {}", e))?;
+
+    svg::validate_input(&content).map_err(|e| format!("Validation error: {:?}", e))
+}
+
+fn write_output(output: Option<&Path>, content: &str, kind: &str) -> Result<(), String> {
+    match output {
         Some(path) => {
-            fs::write(&path, svg_content)
+            fs::write(path, content)
                 .map_err(|e| format!("Failed to write to output file: {}", e))?;
-            println!("SVG successfully written to: {}", path.display());
+            println!("{} successfully written to: {}", kind, path.display());
         }
         None => {
             io::stdout()
-                .write_all(svg_content.as_bytes())
+                .write_all(content.as_bytes())
                 .map_err(|e| format!("Failed to write to stdout: {}", e))?;
         }
     }
 
     Ok(())
 }
+
+fn format_diff(diff: &svg::StarsDiff) -> String {
+    let mut lines = Vec::new();
+
+    for year in &diff.years {
+        let changes = year
+            .changes
+            .iter()
+            .map(|change| format!("day {}: {} -> {}", change.day, change.old, change.new))
+            .collect::<Vec<_>>()
+            .join(", ");
+        lines.push(format!("{}: {:+} ({})", year.year, year.net(), changes));
+    }
+
+    if diff.is_empty() {
+        lines.push("No changes".to_string());
+    }
+    lines.push(format!("Net change: {:+}", diff.net()));
+
+    lines.join("\n")
+}
+
+fn run_diff(old: &Path, new: &Path, output: Option<&Path>) -> Result<(), String> {
+    let old_years = read_years(old)?;
+    let new_years = read_years(new)?;
+
+    println!("{}", format_diff(&svg::diff_years(&old_years, &new_years)));
+
+    if let Some(path) = output {
+        write_output(
+            Some(path),
+            &svg::generate_diff_svg(old_years, new_years),
+            "SVG",
+        )?;
+    }
+
+    Ok(())
+}
+
+fn run_merge(inputs: &[PathBuf], policy: ConflictPolicy) -> Result<String, String> {
+    let mut merged = svg::Years::new();
+
+    for input in inputs {
+        let years = read_years(input)?;
+        merged = svg::merge_years(&merged, &years, policy)
+            .map_err(|e| format!("Failed to merge {}: {}", input.display(), e))?;
+    }
+
+    Ok(svg::format_years(&merged))
+}
+
+fn run_badge(input: &Path, year: Option<usize>) -> Result<String, String> {
+    let years = read_years(input)?;
+    svg::generate_badge(&years, year).map_err(|e| e.to_string())
+}
+
+fn run_timeline(inputs: &[PathBuf], member: Option<&str>) -> Result<String, String> {
+    let mut stars = Vec::new();
+
+    for input in inputs {
+        let content =
+            fs::read_to_string(input).map_err(|e| format!("Failed to read input file: {}", e))?;
+        let leaderboard = svg::parse_leaderboard(&content, member)
+            .map_err(|e| format!("Failed to parse {}: {}", input.display(), e))?;
+        stars.extend(leaderboard);
+    }
+
+    Ok(svg::generate_timeline_svg(&stars))
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    match args.command {
+        Some(Command::Diff { old, new, output }) => run_diff(&old, &new, output.as_deref())?,
+        Some(Command::Merge {
+            inputs,
+            policy,
+            output,
+        }) => {
+            let merged = run_merge(&inputs, policy)?;
+            write_output(output.as_deref(), &merged, "Stars file")?;
+        }
+        Some(Command::Badge {
+            input,
+            year,
+            output,
+        }) => {
+            let badge = run_badge(&input, year)?;
+            write_output(output.as_deref(), &badge, "SVG")?;
+        }
+        Some(Command::Timeline {
+            inputs,
+            member,
+            output,
+        }) => {
+            let timeline = run_timeline(&inputs, member.as_deref())?;
+            write_output(output.as_deref(), &timeline, "SVG")?;
+        }
+        None => {
+            let input = args.input.ok_or("An input file is required")?;
+            let years = read_years(&input)?;
+            write_output(args.output.as_deref(), &svg::generate_svg(years), "SVG")?;
+        }
+    }
+
+    Ok(())
+}
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -76,12 +243,13 @@ mod tests {
         let output_path = output_dir.path().join("output.svg");
 
         let args = Args {
-            input: input_path,
+            command: None,
+            input: Some(input_path),
             output: Some(output_path.clone()),
         };
 
         let result: Result<(), String> = (|| {
-            let content = fs::read_to_string(&args.input)
+            let content = fs::read_to_string(args.input.as_ref().unwrap())
                 .map_err(|e| format!("Failed to read input file: {}", e))?;
 
             let years = match svg::validate_input(&content) {
@@ -117,12 +285,13 @@ mod tests {
         let (input_path, _input_dir) = create_test_file(content);
 
         let args = Args {
-            input: input_path,
+            command: None,
+            input: Some(input_path),
             output: None,
         };
 
         let result: Result<(), String> = (|| {
-            let content = fs::read_to_string(&args.input)
+            let content = fs::read_to_string(args.input.as_ref().unwrap())
                 .map_err(|e| format!("Failed to read input file: {}", e))?;
 
             let _years =
@@ -137,12 +306,13 @@ mod tests {
     #[test]
     fn test_nonexistent_input_file() {
         let args = Args {
-            input: PathBuf::from("nonexistent.txt"),
+            command: None,
+            input: Some(PathBuf::from("nonexistent.txt")),
             output: None,
         };
 
         let result: Result<(), String> = (|| {
-            let _content = fs::read_to_string(&args.input)
+            let _content = fs::read_to_string(args.input.as_ref().unwrap())
                 .map_err(|e| format!("Failed to read input file: {}", e))?;
             Ok(())
         })();
@@ -161,4 +331,72 @@ mod tests {
         println!("Validation result: {:?}", result);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_diff_output() {
+        let old = "2024: 2,2,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0";
+        let new = "2024: 2,2,2,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0";
+        let (old_path, _old_dir) = create_test_file(old);
+        let (new_path, _new_dir) = create_test_file(new);
+
+        let diff = svg::diff_years(
+            &read_years(&old_path).unwrap(),
+            &read_years(&new_path).unwrap(),
+        );
+        assert_eq!(
+            format_diff(&diff),
+            "2024: +2 (day 3: 1 -> 2, day 4: 0 -> 1)\nNet change: +2"
+        );
+
+        let output_dir = tempdir().unwrap();
+        let output_path = output_dir.path().join("diff.svg");
+        assert!(run_diff(&old_path, &new_path, Some(&output_path)).is_ok());
+        let output_content = fs::read_to_string(output_path).unwrap();
+        assert!(output_content.contains("earned"));
+    }
+
+    #[test]
+    fn test_merge_files() {
+        let (left, _left_dir) =
+            create_test_file("2024: 2,2,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0");
+        let (right, _right_dir) = create_test_file(
+            "2023: 2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,1,0,0,0,0,0\n\
+             2024: 1,2,2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0",
+        );
+
+        let merged = run_merge(&[left.clone(), right.clone()], ConflictPolicy::Max).unwrap();
+        assert_eq!(
+            merged,
+            "2023: 2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,1,0,0,0,0,0\n\
+             2024: 2,2,2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0\n"
+        );
+
+        assert!(run_merge(&[left, right], ConflictPolicy::Error).is_err());
+    }
+
+    #[test]
+    fn test_timeline() {
+        let (input, _input_dir) = create_test_file(
+            r#"{"event": "2024", "members": {"1": {"name": "alice", "completion_day_level": {
+                "1": {"1": {"get_star_ts": 1733029200}, "2": {"get_star_ts": 1733030000}}
+            }}}}"#,
+        );
+
+        let timeline = run_timeline(std::slice::from_ref(&input), None).unwrap();
+        assert!(timeline.contains("2024 (2)"));
+
+        assert!(run_timeline(&[input], Some("bob")).is_err());
+    }
+
+    #[test]
+    fn test_badge() {
+        let (input, _input_dir) =
+            create_test_file("2024: 2,2,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0");
+
+        assert!(run_badge(&input, None).unwrap().contains("AoC: ⭐ 5"));
+        assert!(run_badge(&input, Some(2024))
+            .unwrap()
+            .contains("2024: ⭐ 5/50"));
+        assert!(run_badge(&input, Some(2015)).is_err());
+    }
 }
@@ -0,0 +1,263 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::{escape_xml, Years};
+
+const BADGE_HEIGHT: i32 = 20;
+const TEXT_PADDING: i32 = 10;
+const LABEL_COLOUR: &str = "#555";
+const COMPLETE_COLOUR: &str = "#fbbf24";
+const PARTIAL_COLOUR: &str = "#6b7280";
+const EMPTY_COLOUR: &str = "#9f9f9f";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BadgeError {
+    YearNotFound(usize),
+}
+
+impl fmt::Display for BadgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::YearNotFound(year) => write!(f, "Year {} not found in stars file", year),
+        }
+    }
+}
+
+impl Error for BadgeError {}
+
+/// The label, message and colour of a badge, either for the total stars or a single year
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadgeContent {
+    pub label: String,
+    pub message: String,
+    /// Hex colour of the message side of the badge, including the leading `#`
+    pub colour: &'static str,
+}
+
+impl BadgeContent {
+    pub fn new(years: &Years, year: Option<usize>) -> Result<Self, BadgeError> {
+        let count_stars = |days: &[u8]| days.iter().map(|&d| d as usize).sum::<usize>();
+
+        let (label, message, stars, max_stars) = match year {
+            Some(year) => {
+                let (_, days) = years
+                    .iter()
+                    .find(|(y, _)| *y == year)
+                    .ok_or(BadgeError::YearNotFound(year))?;
+                let stars = count_stars(days);
+                let max_stars = days.len() * 2;
+                (
+                    year.to_string(),
+                    format!("⭐ {}/{}", stars, max_stars),
+                    stars,
+                    max_stars,
+                )
+            }
+            None => {
+                let stars = years.iter().map(|(_, days)| count_stars(days)).sum();
+                let max_stars = years.iter().map(|(_, days)| days.len() * 2).sum();
+                ("AoC".to_string(), format!("⭐ {}", stars), stars, max_stars)
+            }
+        };
+
+        let colour = if stars == 0 {
+            EMPTY_COLOUR
+        } else if stars == max_stars {
+            COMPLETE_COLOUR
+        } else {
+            PARTIAL_COLOUR
+        };
+
+        Ok(Self {
+            label,
+            message,
+            colour,
+        })
+    }
+}
+
+/// The text and shadow colours for text on the background, dark on light backgrounds like the
+/// complete colour so the text stays readable. Uses the same brightness cutoff as shields.io.
+fn text_colours(background: &str) -> (&'static str, &'static str) {
+    let hex = background.trim_start_matches('#');
+    let channel = |i: usize| {
+        let value = match hex.len() {
+            3 => hex.get(i..i + 1).map(|c| c.repeat(2)),
+            _ => hex.get(i * 2..i * 2 + 2).map(str::to_string),
+        };
+        value
+            .and_then(|v| u8::from_str_radix(&v, 16).ok())
+            .unwrap_or_default() as f64
+    };
+    let brightness = (channel(0) * 299.0 + channel(1) * 587.0 + channel(2) * 114.0) / 255_000.0;
+
+    if brightness > 0.69 {
+        ("#333", "#ccc")
+    } else {
+        ("#fff", "#010101")
+    }
+}
+
+/// Estimate the rendered width of text in 11px Verdana, which is what shields.io badges use
+fn text_width(text: &str) -> i32 {
+    let width: f64 = text
+        .chars()
+        .map(|c| match c {
+            ' ' => 3.9,
+            '0'..='9' => 7.0,
+            'i' | 'l' | 'j' | '.' | ',' | ':' | ';' | '|' | '!' | '\'' => 3.1,
+            'f' | 't' | 'r' | 'I' | '/' | '(' | ')' | '-' => 4.6,
+            'm' | 'w' => 10.6,
+            'M' | 'W' => 11.4,
+            'A'..='Z' => 7.6,
+            'a'..='z' => 6.6,
+            c if c.is_ascii() => 7.0,
+            // Emoji and other wide characters
+            _ => 13.0,
+        })
+        .sum();
+
+    width.ceil() as i32
+}
+
+/// Render a shields.io style badge, a grey label on the left and a coloured message on the right
+pub fn render_badge(content: &BadgeContent) -> String {
+    let label_width = text_width(&content.label) + TEXT_PADDING * 2;
+    let message_width = text_width(&content.message) + TEXT_PADDING * 2;
+    let width = label_width + message_width;
+    let label = escape_xml(&content.label);
+    let message = escape_xml(&content.message);
+    let title = format!("{}: {}", label, message);
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" role="img" aria-label="{}">"#,
+        width, BADGE_HEIGHT, title
+    );
+    svg.push_str(&format!("<title>{}</title>", title));
+    svg.push_str(
+        r##"<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>"##,
+    );
+    svg.push_str(&format!(
+        r##"<clipPath id="r"><rect width="{}" height="{}" rx="3" fill="#fff"/></clipPath>"##,
+        width, BADGE_HEIGHT
+    ));
+    svg.push_str(&format!(
+        r#"<g clip-path="url(#r)"><rect width="{}" height="{}" fill="{}"/><rect x="{}" width="{}" height="{}" fill="{}"/><rect width="{}" height="{}" fill="url(#s)"/></g>"#,
+        label_width,
+        BADGE_HEIGHT,
+        LABEL_COLOUR,
+        label_width,
+        message_width,
+        BADGE_HEIGHT,
+        content.colour,
+        width,
+        BADGE_HEIGHT
+    ));
+    svg.push_str(
+        r##"<g text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11">"##,
+    );
+    for (text, center_x, background) in [
+        (&label, label_width / 2, LABEL_COLOUR),
+        (
+            &message,
+            label_width + message_width / 2,
+            content.colour,
+        ),
+    ] {
+        // Drop shadow first, then the text itself
+        let (fill, shadow) = text_colours(background);
+        svg.push_str(&format!(
+            r#"<text x="{}" y="15" fill="{}" fill-opacity=".3">{}</text><text x="{}" y="14" fill="{}">{}</text>"#,
+            center_x, shadow, text, center_x, fill, text
+        ));
+    }
+    svg.push_str("</g></svg>");
+
+    svg
+}
+
+/// Generate a badge with the total stars, or the stars for a single year when one is given
+pub fn generate_badge(years: &Years, year: Option<usize>) -> Result<String, BadgeError> {
+    BadgeContent::new(years, year).map(|content| render_badge(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_total_badge_content() {
+        let years: Years = vec![(2023, vec![2; 25]), (2024, vec![2, 1, 0, 0])];
+        let content = BadgeContent::new(&years, None).unwrap();
+        assert_eq!(content.label, "AoC");
+        assert_eq!(content.message, "⭐ 53");
+        assert_eq!(content.colour, PARTIAL_COLOUR);
+    }
+
+    #[test]
+    fn test_year_badge_content() {
+        let years: Years = vec![(2023, vec![2; 25]), (2024, vec![0; 25])];
+
+        let complete = BadgeContent::new(&years, Some(2023)).unwrap();
+        assert_eq!(complete.label, "2023");
+        assert_eq!(complete.message, "⭐ 50/50");
+        assert_eq!(complete.colour, COMPLETE_COLOUR);
+
+        let empty = BadgeContent::new(&years, Some(2024)).unwrap();
+        assert_eq!(empty.message, "⭐ 0/50");
+        assert_eq!(empty.colour, EMPTY_COLOUR);
+
+        assert_eq!(
+            BadgeContent::new(&years, Some(2015)),
+            Err(BadgeError::YearNotFound(2015))
+        );
+    }
+
+    #[test]
+    fn test_text_width() {
+        assert_eq!(text_width(""), 0);
+        assert_eq!(text_width("2024"), 28);
+        assert!(text_width("⭐ 14/50") > text_width("14/50"));
+        assert!(text_width("mmm") > text_width("iii"));
+    }
+
+    #[test]
+    fn test_text_colours() {
+        assert_eq!(text_colours(COMPLETE_COLOUR).0, "#333");
+        assert_eq!(text_colours(PARTIAL_COLOUR).0, "#fff");
+        assert_eq!(text_colours(EMPTY_COLOUR).0, "#fff");
+        assert_eq!(text_colours(LABEL_COLOUR).0, "#fff");
+    }
+
+    #[test]
+    fn test_render_badge() {
+        let years: Years = vec![(2024, vec![2, 2, 1])];
+        let svg = generate_badge(&years, Some(2024)).unwrap();
+
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.ends_with("</svg>"));
+        assert!(svg.contains(r#"aria-label="2024: ⭐ 5/6""#));
+        assert_eq!(svg.matches(">2024</text>").count(), 2);
+
+        let complete = generate_badge(&vec![(2024, vec![2, 2])], None).unwrap();
+        assert!(complete.contains(r##"fill="#333">⭐ 4</text>"##));
+        assert!(complete.contains(r##"fill="#fff">AoC</text>"##));
+    }
+
+    #[test]
+    fn test_render_badge_escapes_text() {
+        let content = BadgeContent {
+            label: r#"<AoC & "friends">"#.to_string(),
+            message: "1 < 2".to_string(),
+            colour: PARTIAL_COLOUR,
+        };
+        let svg = render_badge(&content);
+
+        let label = "&lt;AoC &amp; &quot;friends&quot;&gt;";
+        assert!(svg.contains(&format!(r#"aria-label="{}: 1 &lt; 2""#, label)));
+        assert!(svg.contains(&format!("<title>{}: 1 &lt; 2</title>", label)));
+        assert_eq!(svg.matches(&format!(">{}</text>", label)).count(), 2);
+        assert_eq!(svg.matches(">1 &lt; 2</text>").count(), 2);
+        assert!(!svg.contains("<AoC"));
+    }
+}
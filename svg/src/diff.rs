@@ -0,0 +1,196 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{RenderOptions, SvgBuilder, Years};
+
+/// A single day whose star value differs between two datasets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DayChange {
+    /// Day of the month, starting at 1
+    pub day: usize,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl DayChange {
+    pub fn delta(&self) -> i32 {
+        self.new as i32 - self.old as i32
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YearDiff {
+    pub year: usize,
+    pub changes: Vec<DayChange>,
+}
+
+impl YearDiff {
+    pub fn net(&self) -> i32 {
+        self.changes.iter().map(DayChange::delta).sum()
+    }
+}
+
+/// The changes between two datasets, only containing years that have changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StarsDiff {
+    pub years: Vec<YearDiff>,
+}
+
+impl StarsDiff {
+    pub fn net(&self) -> i32 {
+        self.years.iter().map(YearDiff::net).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.years.is_empty()
+    }
+
+    fn change(&self, year: usize, day_index: usize) -> Option<&DayChange> {
+        self.years
+            .iter()
+            .find(|year_diff| year_diff.year == year)
+            .and_then(|year_diff| year_diff.changes.iter().find(|c| c.day == day_index + 1))
+    }
+}
+
+/// Compare two validated datasets. A year missing from one side is treated as having no stars.
+pub fn diff_years(old: &Years, new: &Years) -> StarsDiff {
+    let old_by_year: BTreeMap<usize, &[u8]> = old
+        .iter()
+        .map(|(year, days)| (*year, days.as_slice()))
+        .collect();
+    let new_by_year: BTreeMap<usize, &[u8]> = new
+        .iter()
+        .map(|(year, days)| (*year, days.as_slice()))
+        .collect();
+    let all_years: BTreeSet<usize> = old_by_year
+        .keys()
+        .chain(new_by_year.keys())
+        .copied()
+        .collect();
+
+    let years = all_years
+        .into_iter()
+        .filter_map(|year| {
+            let old_days = old_by_year.get(&year).copied().unwrap_or_default();
+            let new_days = new_by_year.get(&year).copied().unwrap_or_default();
+            let num_days = old_days.len().max(new_days.len());
+
+            let changes: Vec<DayChange> = (0..num_days)
+                .filter_map(|i| {
+                    let old = old_days.get(i).copied().unwrap_or(0);
+                    let new = new_days.get(i).copied().unwrap_or(0);
+                    (old != new).then_some(DayChange {
+                        day: i + 1,
+                        old,
+                        new,
+                    })
+                })
+                .collect();
+
+            (!changes.is_empty()).then_some(YearDiff { year, changes })
+        })
+        .collect();
+
+    StarsDiff { years }
+}
+
+/// Render the new dataset, with stars earned since the old dataset outlined in an accent colour
+/// and stars that were lost drawn as empty red stars.
+pub fn generate_diff_svg(old: Years, new: Years) -> String {
+    let diff = diff_years(&old, &new);
+    let num_days = new
+        .iter()
+        .chain(old.iter())
+        .map(|(_, days)| days.len())
+        .max()
+        .unwrap_or(0);
+
+    // Years that only exist in the old dataset are still shown, so lost stars are visible
+    let mut years: BTreeMap<usize, Vec<u8>> = old
+        .iter()
+        .map(|(year, _)| (*year, vec![0; num_days]))
+        .collect();
+    years.extend(new);
+    let years: Years = years.into_iter().collect();
+
+    let num_days = num_days as i32;
+    let num_years = years.len() as i32;
+
+    let mut builder = SvgBuilder::new(num_days, num_years, &RenderOptions::default());
+    builder.add_grid(num_days, num_years);
+    builder.add_year_labels(&years.iter().map(|(year, _)| *year).collect::<Vec<_>>());
+    builder.add_day_labels(num_days);
+    builder.add_highlighted_stars(&years, |year, day_index| {
+        let change = diff.change(year, day_index)?;
+        Some(if change.delta() > 0 { "earned" } else { "lost" })
+    });
+    builder.add_footer_note(&format!("Change: {:+}", diff.net()));
+    builder.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_identical() {
+        let years: Years = vec![(2023, vec![2, 1, 0])];
+        let diff = diff_years(&years, &years);
+        assert!(diff.is_empty());
+        assert_eq!(diff.net(), 0);
+    }
+
+    #[test]
+    fn test_diff_gained_and_lost() {
+        let old: Years = vec![(2023, vec![2, 1, 0]), (2024, vec![1, 0, 0])];
+        let new: Years = vec![(2023, vec![2, 2, 1]), (2024, vec![0, 0, 0])];
+        let diff = diff_years(&old, &new);
+
+        assert_eq!(diff.years.len(), 2);
+        assert_eq!(diff.years[0].year, 2023);
+        assert_eq!(
+            diff.years[0].changes,
+            vec![
+                DayChange {
+                    day: 2,
+                    old: 1,
+                    new: 2
+                },
+                DayChange {
+                    day: 3,
+                    old: 0,
+                    new: 1
+                },
+            ]
+        );
+        assert_eq!(diff.years[0].net(), 2);
+        assert_eq!(diff.years[1].net(), -1);
+        assert_eq!(diff.net(), 1);
+    }
+
+    #[test]
+    fn test_diff_missing_years() {
+        let old: Years = vec![(2022, vec![1, 0])];
+        let new: Years = vec![(2023, vec![2, 2])];
+        let diff = diff_years(&old, &new);
+
+        assert_eq!(diff.years.len(), 2);
+        assert_eq!(diff.years[0].net(), -1);
+        assert_eq!(diff.years[1].net(), 4);
+        assert_eq!(diff.net(), 3);
+    }
+
+    #[test]
+    fn test_diff_svg_highlights() {
+        let old: Years = vec![(2023, vec![2, 1, 0, 1])];
+        let new: Years = vec![(2023, vec![2, 2, 1, 0])];
+        let svg = generate_diff_svg(old, new);
+
+        assert_eq!(svg.matches(r#"class="star gold earned"#).count(), 1);
+        assert_eq!(svg.matches(r#"class="star silver earned"#).count(), 1);
+        assert_eq!(svg.matches(r#"class="star empty lost"#).count(), 1);
+        assert!(svg.contains(".earned {") && svg.contains(".lost {"));
+        assert!(svg.contains("Total stars: 5"));
+        assert!(svg.contains("Change: +1"));
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{escape_xml, THEME_STYLE};
+
+const WIDTH: i32 = 420;
+const PADDING: i32 = 20;
+const TITLE_Y: i32 = 34;
+const LINE_HEIGHT: i32 = 18;
+const MAX_LINE_CHARS: usize = 60;
+const MAX_LINES: usize = 6;
+
+/// Wrap text on whitespace into lines of at most `MAX_LINE_CHARS`, cutting it off after
+/// `MAX_LINES` lines. Words longer than a line, like URLs, are broken up.
+fn wrap_text(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut current = String::new();
+
+    let chars: Vec<Vec<char>> = text
+        .split_whitespace()
+        .map(|word| word.chars().collect())
+        .collect();
+    let words = chars
+        .iter()
+        .flat_map(|word| word.chunks(MAX_LINE_CHARS))
+        .map(|chunk| chunk.iter().collect::<String>());
+
+    for word in words {
+        if !current.is_empty() && current.chars().count() + word.chars().count() >= MAX_LINE_CHARS {
+            lines.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(&word);
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    if lines.len() > MAX_LINES {
+        lines.truncate(MAX_LINES);
+        lines[MAX_LINES - 1].push('…');
+    }
+    lines
+}
+
+/// Generate a small image explaining why the stars couldn't be shown, so embeds show the problem
+/// instead of a broken image
+pub fn generate_error_svg(title: &str, message: &str) -> String {
+    let lines = wrap_text(message);
+    let height = TITLE_Y + PADDING + lines.len() as i32 * LINE_HEIGHT;
+
+    let mut content = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#,
+        WIDTH, height, WIDTH, height
+    );
+    content.push_str(
+        r#"
+            <style>"#,
+    );
+    content.push_str(THEME_STYLE);
+    content.push_str(
+        r#"
+                .error-title { font-family: Arial; font-size: 14px; font-weight: bold; }
+                .error-message { font-family: Arial; font-size: 12px; }
+                .error-icon { fill: #ef4444; font-family: Arial; font-size: 16px; }
+                .matrix-border { fill: none; stroke-width: 1; }
+                .text { font-family: Arial; }
+            </style>"#,
+    );
+
+    content.push_str(&format!(
+        r#"<rect x="0.5" y="0.5" width="{}" height="{}" rx="6" class="matrix-border"/>"#,
+        WIDTH - 1,
+        height - 1
+    ));
+    content.push_str(&format!(
+        r#"<text x="{}" y="{}" class="error-icon">⚠</text>"#,
+        PADDING, TITLE_Y
+    ));
+    content.push_str(&format!(
+        r#"<text x="{}" y="{}" class="error-title text">{}</text>"#,
+        PADDING + 24,
+        TITLE_Y - 1,
+        escape_xml(title)
+    ));
+
+    for (i, line) in lines.iter().enumerate() {
+        content.push_str(&format!(
+            r#"<text x="{}" y="{}" class="error-message text">{}</text>"#,
+            PADDING,
+            TITLE_Y + PADDING + 4 + i as i32 * LINE_HEIGHT,
+            escape_xml(line)
+        ));
+    }
+
+    content.push_str("</svg>");
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_text() {
+        assert!(wrap_text("").is_empty());
+        assert_eq!(wrap_text("File not found"), vec!["File not found"]);
+
+        let long = "word ".repeat(100);
+        let lines = wrap_text(&long);
+        assert_eq!(lines.len(), MAX_LINES);
+        assert!(lines
+            .iter()
+            .all(|line| line.chars().count() <= MAX_LINE_CHARS));
+        assert!(lines[MAX_LINES - 1].ends_with('…'));
+
+        let url = format!("https://example.com/{}", "a".repeat(100));
+        let lines = wrap_text(&format!("Unexpected status 502 from {}", url));
+        assert_eq!(lines.len(), 3);
+        assert!(lines
+            .iter()
+            .all(|line| line.chars().count() <= MAX_LINE_CHARS));
+        assert_eq!(lines.concat(), format!("Unexpected status 502 from{}", url));
+    }
+
+    #[test]
+    fn test_error_svg() {
+        let svg = generate_error_svg(
+            "Invalid stars file",
+            "Invalid line format on line 3: <oops>",
+        );
+
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.ends_with("</svg>"));
+        assert!(svg.contains("prefers-color-scheme: dark"));
+        assert!(svg.contains("Invalid stars file"));
+        assert!(svg.contains("line 3: &lt;oops&gt;"));
+    }
+}
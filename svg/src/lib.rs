@@ -1,17 +1,44 @@
+mod badge;
+mod diff;
+mod error;
+mod merge;
+mod options;
+mod timeline;
 mod validation;
 
-pub use validation::validate_input;
+pub use badge::{generate_badge, render_badge, BadgeContent, BadgeError};
+pub use diff::{diff_years, generate_diff_svg, DayChange, StarsDiff, YearDiff};
+pub use error::generate_error_svg;
+pub use merge::{format_years, merge_years, ConflictPolicy, MergeError};
+pub use options::{
+    ParseThemeError, RenderOptions, Theme, DEFAULT_CELL_SIZE, MAX_CELL_SIZE, MIN_CELL_SIZE,
+};
+pub use timeline::{generate_timeline_svg, parse_leaderboard, StarTimestamp, TimelineError};
+pub use validation::{validate_input, ValidationError};
 
-const CELL_SIZE: i32 = 20;
-const FONT_SIZE: i32 = 12;
 const X_OFFSET: i32 = 40;
 const Y_OFFSET: i32 = 60;
 const YEAR_Y_OFFSET: i32 = 5;
 const PADDING: i32 = 20;
 const MATRIX_BORDER: i32 = 1;
+const TITLE_HEIGHT: i32 = 30;
+const TITLE_CHAR_WIDTH: i32 = 9;
 
-type Year = (usize, Vec<u8>);
-type Years = Vec<Year>;
+pub type Year = (usize, Vec<u8>);
+pub type Years = Vec<Year>;
+
+/// Light and dark mode colours shared by all the generated SVGs
+const THEME_STYLE: &str = r#"
+                @media (prefers-color-scheme: light) {
+                    .text { fill: #24292f; }
+                    .grid-line { stroke: #24292f; }
+                    .matrix-border { stroke: #24292f; }
+                }
+                @media (prefers-color-scheme: dark) {
+                    .text { fill: #c9d1d9; }
+                    .grid-line { stroke: #c9d1d9; }
+                    .matrix-border { stroke: #c9d1d9; }
+                }"#;
 
 #[derive(Copy, Clone)]
 enum Star {
@@ -30,20 +57,46 @@ impl From<u8> for Star {
     }
 }
 
+/// Escape text so it can be safely placed within an SVG element or attribute
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
 struct SvgBuilder {
     content: String,
     width: i32,
     height: i32,
     matrix_width: i32,
     matrix_height: i32,
+    cell_size: i32,
+    star_font_size: i32,
+    y_offset: i32,
+    show_totals: bool,
 }
 
 impl SvgBuilder {
-    fn new(num_days: i32, num_years: i32) -> Self {
-        let matrix_width = (num_days + 1) * CELL_SIZE;
-        let matrix_height = num_years * CELL_SIZE;
-        let width = X_OFFSET + matrix_width + PADDING * 2;
-        let height = Y_OFFSET + matrix_height + PADDING * 4;
+    fn new(num_days: i32, num_years: i32, options: &RenderOptions) -> Self {
+        let cell_size = options.cell_size;
+        let show_totals = !options.hide_totals;
+        let total_columns = if show_totals { 1 } else { 0 };
+        let y_offset = Y_OFFSET + options.title.as_ref().map_or(0, |_| TITLE_HEIGHT);
+
+        let matrix_width = (num_days + total_columns) * cell_size;
+        let matrix_height = num_years * cell_size;
+        let title_width = options.title.as_ref().map_or(0, |title| {
+            title.chars().count() as i32 * TITLE_CHAR_WIDTH + PADDING * 2
+        });
+        let width = (X_OFFSET + matrix_width + PADDING * 2).max(title_width);
+        let footer_height = if show_totals {
+            PADDING * 4
+        } else {
+            PADDING * 2
+        };
+        let height = y_offset + matrix_height + footer_height;
 
         let mut builder = Self {
             content: String::new(),
@@ -51,66 +104,85 @@ impl SvgBuilder {
             height,
             matrix_width,
             matrix_height,
+            cell_size,
+            // Keeps the stars at 12px for the default cell size
+            star_font_size: cell_size * 3 / 5,
+            y_offset,
+            show_totals,
         };
 
-        builder.add_header();
+        builder.add_header(options);
         builder
     }
 
-    fn add_header(&mut self) {
+    fn add_header(&mut self, options: &RenderOptions) {
         self.content.push_str(&format!(
             r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#,
             self.width, self.height, self.width, self.height
         ));
         self.content.push_str(
             r#"
-            <style>
-                @media (prefers-color-scheme: light) {
-                    .text { fill: #24292f; }
-                    .grid-line { stroke: #24292f; }
-                    .matrix-border { stroke: #24292f; }
-                }
-                @media (prefers-color-scheme: dark) {
-                    .text { fill: #c9d1d9; }
-                    .grid-line { stroke: #c9d1d9; }
-                    .matrix-border { stroke: #c9d1d9; }
-                }
-                .year-label { font-family: Arial; font-size: 12px; }
-                .day-label { font-family: Arial; font-size: 12px; }
-                .total-label { font-family: Arial; font-size: 12px; font-weight: bold; }
-                .grand-total { font-family: Arial; font-size: 14px; font-weight: bold; }
-                .star { font-family: Arial; font-size: 12px; }
-                .silver { fill: #6b7280; }
-                .gold { fill: #fbbf24; }
-                .matrix-border { fill: none; stroke-width: 1; }
-                .grid-line { stroke-width: 0.5; stroke-opacity: 0.1; }
-                .text { font-family: Arial; }
-            </style>"#,
+            <style>"#,
         );
+        self.content.push_str(&options.theme.style());
+        self.content.push_str(&format!(
+            r#"
+                .title {{ font-family: Arial; font-size: 16px; font-weight: bold; }}
+                .year-label {{ font-family: Arial; font-size: 12px; }}
+                .day-label {{ font-family: Arial; font-size: 12px; }}
+                .total-label {{ font-family: Arial; font-size: 12px; font-weight: bold; }}
+                .grand-total {{ font-family: Arial; font-size: 14px; font-weight: bold; }}
+                .star {{ font-family: Arial; font-size: {}px; }}
+                .matrix-border {{ fill: none; stroke-width: 1; }}
+                .grid-line {{ stroke-width: 0.5; stroke-opacity: 0.1; }}
+                .text {{ font-family: Arial; }}
+            </style>"#,
+            self.star_font_size
+        ));
+
+        if options.theme.has_background() {
+            self.content
+                .push_str(r#"<rect width="100%" height="100%" class="background"/>"#);
+        }
+
+        if let Some(title) = &options.title {
+            self.content.push_str(&format!(
+                r#"<text x="{}" y="{}" class="title text" text-anchor="middle">{}</text>"#,
+                self.width / 2,
+                PADDING + TITLE_HEIGHT / 4,
+                escape_xml(title)
+            ));
+        }
 
         self.content.push_str(&format!(
             r#"<rect x="{}" y="{}" width="{}" height="{}" class="matrix-border"/>"#,
             X_OFFSET - MATRIX_BORDER,
-            Y_OFFSET - MATRIX_BORDER,
+            self.y_offset - MATRIX_BORDER,
             self.matrix_width + MATRIX_BORDER * 2,
             self.matrix_height + MATRIX_BORDER * 2
         ));
     }
 
     fn add_grid(&mut self, num_days: i32, num_years: i32) {
-        for i in 0..=(num_days + 1) {
-            let x = X_OFFSET + i * CELL_SIZE;
+        let num_columns = if self.show_totals {
+            num_days + 1
+        } else {
+            num_days
+        };
+
+        for i in 0..=num_columns {
+            let x = X_OFFSET + i * self.cell_size;
             self.content.push_str(&format!(
                 r#"<line x1="{}" y1="{}" x2="{}" y2="{}" class="grid-line"/>"#,
                 x,
-                Y_OFFSET,
+                self.y_offset,
                 x,
-                Y_OFFSET + self.matrix_height
+                self.y_offset + self.matrix_height
             ));
         }
 
         for i in 0..=num_years {
-            let y = Y_OFFSET + i * CELL_SIZE;
+            let y = self.y_offset + i * self.cell_size;
             self.content.push_str(&format!(
                 r#"<line x1="{}" y1="{}" x2="{}" y2="{}" class="grid-line"/>"#,
                 X_OFFSET,
@@ -123,11 +195,11 @@ impl SvgBuilder {
 
     fn add_year_labels(&mut self, years: &[usize]) {
         for (i, year) in years.iter().enumerate() {
-            let y_position = Y_OFFSET + YEAR_Y_OFFSET + (i as i32) * CELL_SIZE;
+            let y_position = self.y_offset + YEAR_Y_OFFSET + (i as i32) * self.cell_size;
             self.content.push_str(&format!(
                 r#"<text x="{}" y="{}" class="year-label text" text-anchor="end">{}</text>"#,
                 X_OFFSET - PADDING / 2,
-                y_position + CELL_SIZE / 2,
+                y_position + self.cell_size / 2,
                 year,
             ));
         }
@@ -135,14 +207,14 @@ impl SvgBuilder {
 
     fn add_day_labels(&mut self, num_days: i32) {
         for day in 0..num_days {
-            let x_position = X_OFFSET + day * CELL_SIZE;
+            let x_position = X_OFFSET + day * self.cell_size;
             let day_num = day + 1;
 
             if day_num < 10 {
                 self.content.push_str(&format!(
                     r#"<text x="{}" y="{}" class="day-label text" text-anchor="middle">{}</text>"#,
-                    x_position + CELL_SIZE / 2,
-                    Y_OFFSET - PADDING / 4,
+                    x_position + self.cell_size / 2,
+                    self.y_offset - PADDING / 4,
                     day_num
                 ));
             } else {
@@ -151,11 +223,11 @@ impl SvgBuilder {
                 self.content.push_str(&format!(
                     r#"<text x="{}" y="{}" class="day-label text" text-anchor="middle">{}</text>
                     <text x="{}" y="{}" class="day-label text" text-anchor="middle">{}</text>"#,
-                    x_position + CELL_SIZE / 2,
-                    Y_OFFSET - PADDING - 2,
+                    x_position + self.cell_size / 2,
+                    self.y_offset - PADDING - 2,
                     tens,
-                    x_position + CELL_SIZE / 2,
-                    Y_OFFSET - PADDING / 4,
+                    x_position + self.cell_size / 2,
+                    self.y_offset - PADDING / 4,
                     ones
                 ));
             }
@@ -163,52 +235,95 @@ impl SvgBuilder {
     }
 
     fn add_stars(&mut self, years: &Years) {
+        self.push_stars(years, |_, _| None);
+    }
+
+    /// Add the stars for each year, with `highlight` returning an extra class for a given year and
+    /// day index, along with the styles for the `earned` and `lost` classes. Cells without a star
+    /// are still drawn, as an empty star, when highlighted.
+    fn add_highlighted_stars<F>(&mut self, years: &Years, highlight: F)
+    where
+        F: Fn(usize, usize) -> Option<&'static str>,
+    {
+        self.content.push_str(
+            r#"
+            <style>
+                .earned { stroke: #f97316; stroke-width: 1; }
+                .lost { fill: #ef4444; }
+            </style>"#,
+        );
+        self.push_stars(years, highlight);
+    }
+
+    fn push_stars<F>(&mut self, years: &Years, highlight: F)
+    where
+        F: Fn(usize, usize) -> Option<&'static str>,
+    {
         let mut grand_total = 0;
 
-        for (i, (_, days)) in years.iter().enumerate() {
-            let y_position = Y_OFFSET + i as i32 * CELL_SIZE;
+        for (i, (year, days)) in years.iter().enumerate() {
+            let y_position = self.y_offset + i as i32 * self.cell_size;
             let mut year_total = 0;
 
             for (day_index, &value) in days.iter().enumerate() {
                 let star: Star = value.into();
-                if matches!(star, Star::None) {
+                let extra_class = highlight(*year, day_index);
+                if matches!(star, Star::None) && extra_class.is_none() {
                     continue;
                 }
 
                 year_total += value as i32;
-                let x_position = X_OFFSET + day_index as i32 * CELL_SIZE;
-                let star_class = match star {
-                    Star::Silver => "silver",
-                    Star::Gold => "gold",
-                    Star::None => continue,
+                let x_position = X_OFFSET + day_index as i32 * self.cell_size;
+                let (glyph, star_class) = match star {
+                    Star::Silver => ("★", "silver"),
+                    Star::Gold => ("★", "gold"),
+                    Star::None => ("☆", "empty"),
+                };
+                let class = match extra_class {
+                    Some(extra) => format!("star {} {}", star_class, extra),
+                    None => format!("star {}", star_class),
                 };
 
                 self.content.push_str(&format!(
-                    r#"<text x="{}" y="{}" class="star {}" text-anchor="middle">★</text>"#,
-                    x_position + CELL_SIZE / 2,
-                    y_position + CELL_SIZE / 2 + FONT_SIZE / 3,
-                    star_class
+                    r#"<text x="{}" y="{}" class="{}" text-anchor="middle">{}</text>"#,
+                    x_position + self.cell_size / 2,
+                    y_position + self.cell_size / 2 + self.star_font_size / 3,
+                    class,
+                    glyph
                 ));
             }
 
-            let total_x = X_OFFSET + days.len() as i32 * CELL_SIZE;
-            self.content.push_str(&format!(
-                r#"<text x="{}" y="{}" class="total-label text" text-anchor="middle">{}</text>"#,
-                total_x + CELL_SIZE / 2,
-                y_position + CELL_SIZE / 2 + FONT_SIZE / 3,
-                year_total
-            ));
+            if self.show_totals {
+                let total_x = X_OFFSET + days.len() as i32 * self.cell_size;
+                self.content.push_str(&format!(
+                    r#"<text x="{}" y="{}" class="total-label text" text-anchor="middle">{}</text>"#,
+                    total_x + self.cell_size / 2,
+                    y_position + self.cell_size / 2 + self.star_font_size / 3,
+                    year_total
+                ));
+            }
 
             grand_total += year_total;
         }
 
+        if self.show_totals {
+            let center_x = X_OFFSET + self.matrix_width / 2;
+            let total_y = self.y_offset + self.matrix_height + PADDING * 2;
+            self.content.push_str(&format!(
+                r#"<text x="{}" y="{}" class="grand-total text" text-anchor="middle">Total stars: {}</text>"#,
+                center_x,
+                total_y,
+                grand_total
+            ));
+        }
+    }
+
+    fn add_footer_note(&mut self, note: &str) {
         let center_x = X_OFFSET + self.matrix_width / 2;
-        let total_y = Y_OFFSET + self.matrix_height + PADDING * 2;
+        let note_y = self.y_offset + self.matrix_height + PADDING * 3;
         self.content.push_str(&format!(
-            r#"<text x="{}" y="{}" class="grand-total text" text-anchor="middle">Total stars: {}</text>"#,
-            center_x,
-            total_y,
-            grand_total
+            r#"<text x="{}" y="{}" class="total-label text" text-anchor="middle">{}</text>"#,
+            center_x, note_y, note
         ));
     }
 
@@ -219,10 +334,19 @@ impl SvgBuilder {
 }
 
 pub fn generate_svg(years: Years) -> String {
+    generate_svg_with_options(years, &RenderOptions::default())
+}
+
+/// Generate the stars grid, with the theme and layout controlled by `options`
+pub fn generate_svg_with_options(mut years: Years, options: &RenderOptions) -> String {
+    if let Some(range) = &options.years {
+        years.retain(|(year, _)| range.contains(year));
+    }
+
     let num_years = years.len() as i32;
     let num_days = years.first().map_or(0, |(_, days)| days.len()) as i32;
 
-    let mut builder = SvgBuilder::new(num_days, num_years);
+    let mut builder = SvgBuilder::new(num_days, num_years, options);
     builder.add_grid(num_days, num_years);
     builder.add_year_labels(&years.iter().map(|(year, _)| *year).collect::<Vec<_>>());
     builder.add_day_labels(num_days);
@@ -295,6 +419,8 @@ mod tests {
         assert!(svg.contains("prefers-color-scheme: dark"));
         assert!(svg.contains(".silver {"));
         assert!(svg.contains(".gold {"));
+        // The highlight classes are only needed by diffs
+        assert!(!svg.contains(".earned {"));
     }
 
     // Helper function to count occurrences of a pattern in a string
@@ -316,4 +442,35 @@ mod tests {
         assert_eq!(gold_stars, 2, "Should have exactly 2 gold stars");
         assert!(svg.contains("Total stars: 6"));
     }
+
+    #[test]
+    fn test_default_options_match_generate_svg() {
+        let years: Years = vec![(2023, vec![1, 2, 0])];
+        assert_eq!(
+            generate_svg(years.clone()),
+            generate_svg_with_options(years, &RenderOptions::default())
+        );
+    }
+
+    #[test]
+    fn test_render_options() {
+        let years: Years = vec![(2018, vec![2, 2]), (2019, vec![1, 0]), (2020, vec![2, 1])];
+        let options = RenderOptions {
+            theme: Theme::AocClassic,
+            cell_size: 30,
+            hide_totals: true,
+            years: Some(2019..=2020),
+            title: Some("Stars & <stripes>".to_string()),
+        };
+        let svg = generate_svg_with_options(years, &options);
+
+        assert!(svg.contains(r#"class="background""#));
+        assert!(!svg.contains("prefers-color-scheme"));
+        assert!(svg.contains("font-size: 18px"));
+        assert!(!svg.contains("Total stars"));
+        assert!(!svg.contains("2018"));
+        assert!(svg.contains("2019"));
+        assert!(svg.contains("Stars &amp; &lt;stripes&gt;"));
+        assert_eq!(count_occurrences(&svg, r#"class="star "#), 3);
+    }
 }
@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+
+use crate::Years;
+
+/// How to resolve a day that has different star values in the datasets being merged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum ConflictPolicy {
+    /// Take the highest star value for the day
+    #[default]
+    Max,
+    /// Fail the merge on the first conflicting day
+    Error,
+    /// Keep the value from the left, or earlier, dataset
+    PreferLeft,
+    /// Keep the value from the right, or later, dataset
+    PreferRight,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MergeError {
+    Conflict {
+        year: usize,
+        day: usize,
+        left: u8,
+        right: u8,
+    },
+    DayCountMismatch {
+        year: usize,
+        left: usize,
+        right: usize,
+    },
+    /// A year appears more than once in one of the datasets
+    DuplicateYear(usize),
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Conflict {
+                year,
+                day,
+                left,
+                right,
+            } => write!(
+                f,
+                "Conflict in year {} day {}: {} on the left, {} on the right",
+                year, day, left, right
+            ),
+            Self::DayCountMismatch { year, left, right } => write!(
+                f,
+                "Year {} has {} days on the left and {} days on the right",
+                year, left, right
+            ),
+            Self::DuplicateYear(year) => write!(f, "Year {} appears more than once", year),
+        }
+    }
+}
+
+impl Error for MergeError {}
+
+/// Fail on the first year that appears twice in the dataset
+fn check_unique_years(years: &Years) -> Result<(), MergeError> {
+    let mut seen = BTreeSet::new();
+    match years.iter().find(|(year, _)| !seen.insert(*year)) {
+        Some((year, _)) => Err(MergeError::DuplicateYear(*year)),
+        None => Ok(()),
+    }
+}
+
+/// Merge two datasets by year, resolving days that differ with the given policy. The result is
+/// sorted by year. Years repeated within a dataset are rejected, as it's unclear which one is
+/// meant.
+pub fn merge_years(
+    left: &Years,
+    right: &Years,
+    policy: ConflictPolicy,
+) -> Result<Years, MergeError> {
+    check_unique_years(left)?;
+    check_unique_years(right)?;
+    let mut merged: BTreeMap<usize, Vec<u8>> = left.iter().cloned().collect();
+
+    for (year, right_days) in right {
+        let Some(left_days) = merged.get_mut(year) else {
+            merged.insert(*year, right_days.clone());
+            continue;
+        };
+
+        if left_days.len() != right_days.len() {
+            return Err(MergeError::DayCountMismatch {
+                year: *year,
+                left: left_days.len(),
+                right: right_days.len(),
+            });
+        }
+
+        for (i, (left_value, &right_value)) in left_days.iter_mut().zip(right_days).enumerate() {
+            if *left_value == right_value {
+                continue;
+            }
+
+            *left_value = match policy {
+                ConflictPolicy::Max => (*left_value).max(right_value),
+                ConflictPolicy::PreferLeft => *left_value,
+                ConflictPolicy::PreferRight => right_value,
+                ConflictPolicy::Error => {
+                    return Err(MergeError::Conflict {
+                        year: *year,
+                        day: i + 1,
+                        left: *left_value,
+                        right: right_value,
+                    })
+                }
+            };
+        }
+    }
+
+    Ok(merged.into_iter().collect())
+}
+
+/// Write a dataset back out in the same text format that `validate_input` accepts
+pub fn format_years(years: &Years) -> String {
+    years
+        .iter()
+        .map(|(year, days)| {
+            let days = days
+                .iter()
+                .map(|day| day.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            format!("{}: {}\n", year, days)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate_input;
+
+    #[test]
+    fn test_merge_disjoint_years() {
+        let left: Years = vec![(2024, vec![2, 0])];
+        let right: Years = vec![(2019, vec![1, 1])];
+        let merged = merge_years(&left, &right, ConflictPolicy::default()).unwrap();
+        assert_eq!(merged, vec![(2019, vec![1, 1]), (2024, vec![2, 0])]);
+    }
+
+    #[test]
+    fn test_merge_policies() {
+        let left: Years = vec![(2023, vec![2, 0, 1])];
+        let right: Years = vec![(2023, vec![1, 1, 1])];
+
+        let max = merge_years(&left, &right, ConflictPolicy::Max).unwrap();
+        assert_eq!(max, vec![(2023, vec![2, 1, 1])]);
+
+        let prefer_left = merge_years(&left, &right, ConflictPolicy::PreferLeft).unwrap();
+        assert_eq!(prefer_left, left);
+
+        let prefer_right = merge_years(&left, &right, ConflictPolicy::PreferRight).unwrap();
+        assert_eq!(prefer_right, right);
+
+        assert_eq!(
+            merge_years(&left, &right, ConflictPolicy::Error),
+            Err(MergeError::Conflict {
+                year: 2023,
+                day: 1,
+                left: 2,
+                right: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_duplicate_years() {
+        let unique: Years = vec![(2024, vec![2, 0])];
+        let duplicated: Years = vec![(2023, vec![2, 2]), (2024, vec![1, 0]), (2024, vec![2, 2])];
+
+        for (left, right) in [(&duplicated, &unique), (&unique, &duplicated)] {
+            assert_eq!(
+                merge_years(left, right, ConflictPolicy::Max),
+                Err(MergeError::DuplicateYear(2024))
+            );
+        }
+    }
+
+    #[test]
+    fn test_format_round_trip() {
+        let input = "2015: 2,2,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0\n\
+                     2016: 2,2,2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0\n";
+        let years = validate_input(input).unwrap();
+        let formatted = format_years(&years);
+        assert_eq!(validate_input(&formatted).unwrap(), years);
+        assert!(formatted.starts_with("2015: 2,2,1,0"));
+    }
+}
@@ -0,0 +1,120 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::THEME_STYLE;
+
+pub const DEFAULT_CELL_SIZE: i32 = 20;
+pub const MIN_CELL_SIZE: i32 = 12;
+pub const MAX_CELL_SIZE: i32 = 48;
+
+/// The colour scheme of the stars grid
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Theme {
+    /// Follows the light or dark mode of the viewer, matching GitHub
+    #[default]
+    Default,
+    /// The dark blue and yellow of the Advent of Code website
+    AocClassic,
+}
+
+impl Theme {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::AocClassic => "aoc-classic",
+        }
+    }
+
+    /// Colour styles for the theme, to be placed within a `<style>` element
+    pub(crate) fn style(&self) -> String {
+        match self {
+            Self::Default => format!(
+                r#"{}
+                .silver {{ fill: #6b7280; }}
+                .gold {{ fill: #fbbf24; }}"#,
+                THEME_STYLE
+            ),
+            Self::AocClassic => r#"
+                .background { fill: #0f0f23; }
+                .text { fill: #cccccc; }
+                .grid-line { stroke: #cccccc; }
+                .matrix-border { stroke: #333340; }
+                .silver { fill: #9999cc; }
+                .gold { fill: #ffff66; }"#
+                .to_string(),
+        }
+    }
+
+    /// Whether the theme paints its own background, rather than using the page background
+    pub(crate) fn has_background(&self) -> bool {
+        matches!(self, Self::AocClassic)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseThemeError(String);
+
+impl fmt::Display for ParseThemeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown theme {}, expected one of: default, aoc-classic",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseThemeError {}
+
+impl FromStr for Theme {
+    type Err = ParseThemeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::Default),
+            "aoc-classic" => Ok(Self::AocClassic),
+            _ => Err(ParseThemeError(s.to_string())),
+        }
+    }
+}
+
+/// Options for how the stars grid is rendered, where the default matches `generate_svg`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderOptions {
+    pub theme: Theme,
+    /// Size of each cell in the grid, between `MIN_CELL_SIZE` and `MAX_CELL_SIZE`
+    pub cell_size: i32,
+    /// Hide the totals column and the grand total below the grid
+    pub hide_totals: bool,
+    /// Only include the years within the range
+    pub years: Option<RangeInclusive<usize>>,
+    /// Optional title shown above the grid
+    pub title: Option<String>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            theme: Theme::default(),
+            cell_size: DEFAULT_CELL_SIZE,
+            hide_totals: false,
+            years: None,
+            title: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_theme_from_str() {
+        assert_eq!("default".parse(), Ok(Theme::Default));
+        assert_eq!("aoc-classic".parse(), Ok(Theme::AocClassic));
+        assert!("neon".parse::<Theme>().is_err());
+        assert_eq!(Theme::AocClassic.name().parse(), Ok(Theme::AocClassic));
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::THEME_STYLE;
+
+const CHART_WIDTH: i64 = 500;
+const CHART_HEIGHT: i64 = 200;
+const X_OFFSET: i64 = 50;
+const Y_OFFSET: i64 = 50;
+const PADDING: i64 = 20;
+const LEGEND_WIDTH: i64 = 80;
+const LEGEND_ROW_HEIGHT: i64 = 16;
+const Y_TICK_STEP: usize = 10;
+const SECONDS_PER_DAY: i64 = 86_400;
+/// Puzzles unlock at midnight EST, which is 05:00 UTC
+const UNLOCK_OFFSET: i64 = 5 * 3600;
+
+const YEAR_COLOURS: [&str; 10] = [
+    "#fbbf24", "#60a5fa", "#34d399", "#f472b6", "#a78bfa", "#f87171", "#2dd4bf", "#fb923c",
+    "#a3e635", "#e879f9",
+];
+
+/// A single star, with the unix timestamp of when it was earned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StarTimestamp {
+    pub year: usize,
+    pub day: u8,
+    pub part: u8,
+    pub timestamp: i64,
+}
+
+#[derive(Debug)]
+pub enum TimelineError {
+    InvalidJson(String),
+    InvalidEvent(String),
+    MemberNotFound(String),
+    AmbiguousMember { count: usize },
+}
+
+impl fmt::Display for TimelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidJson(error) => write!(f, "Invalid leaderboard JSON: {}", error),
+            Self::InvalidEvent(event) => write!(f, "Invalid leaderboard event: {}", event),
+            Self::MemberNotFound(member) => {
+                write!(f, "Member not found in leaderboard: {}", member)
+            }
+            Self::AmbiguousMember { count } => write!(
+                f,
+                "Leaderboard has {} members, a member id or name is required",
+                count
+            ),
+        }
+    }
+}
+
+impl Error for TimelineError {}
+
+#[derive(Deserialize)]
+struct Leaderboard {
+    event: String,
+    members: HashMap<String, LeaderboardMember>,
+}
+
+#[derive(Deserialize)]
+struct LeaderboardMember {
+    name: Option<String>,
+    #[serde(default)]
+    completion_day_level: HashMap<String, HashMap<String, StarLevel>>,
+}
+
+#[derive(Deserialize)]
+struct StarLevel {
+    get_star_ts: i64,
+}
+
+/// Read the star timestamps of a single member from a private leaderboard JSON export. The member
+/// can be picked by id or name, and can be left out when the leaderboard only has one member.
+pub fn parse_leaderboard(
+    json: &str,
+    member: Option<&str>,
+) -> Result<Vec<StarTimestamp>, TimelineError> {
+    let leaderboard: Leaderboard =
+        serde_json::from_str(json).map_err(|e| TimelineError::InvalidJson(e.to_string()))?;
+
+    let year = leaderboard
+        .event
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| TimelineError::InvalidEvent(leaderboard.event.clone()))?;
+
+    let member = match member {
+        Some(wanted) => leaderboard
+            .members
+            .iter()
+            .find(|(id, m)| id.as_str() == wanted || m.name.as_deref() == Some(wanted))
+            .map(|(_, m)| m)
+            .ok_or_else(|| TimelineError::MemberNotFound(wanted.to_string()))?,
+        None if leaderboard.members.len() == 1 => leaderboard.members.values().next().unwrap(),
+        None => {
+            return Err(TimelineError::AmbiguousMember {
+                count: leaderboard.members.len(),
+            })
+        }
+    };
+
+    let mut stars = Vec::new();
+    for (day, parts) in &member.completion_day_level {
+        let day = day
+            .parse::<u8>()
+            .map_err(|e| TimelineError::InvalidJson(format!("day {}: {}", day, e)))?;
+        for (part, level) in parts {
+            let part = part
+                .parse::<u8>()
+                .map_err(|e| TimelineError::InvalidJson(format!("part {}: {}", part, e)))?;
+            stars.push(StarTimestamp {
+                year,
+                day,
+                part,
+                timestamp: level.get_star_ts,
+            });
+        }
+    }
+
+    stars.sort_by_key(|star| (star.timestamp, star.day, star.part));
+    Ok(stars)
+}
+
+/// The unix timestamp of midnight EST on the 1st of December, when the first puzzle of the year
+/// unlocks
+fn year_start(year: usize) -> i64 {
+    // Civil date to days, from http://howardhinnant.github.io/date_algorithms.html
+    let year = year as i64;
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    // December is month 9 when counting from March
+    let doy = (153 * 9 + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    days * SECONDS_PER_DAY + UNLOCK_OFFSET
+}
+
+struct TimelineBuilder {
+    content: String,
+    width: i64,
+    height: i64,
+    /// The longest time since the start of a year, in seconds
+    duration: i64,
+    max_stars: usize,
+}
+
+impl TimelineBuilder {
+    fn new(duration: i64, max_stars: usize, num_years: usize) -> Self {
+        let width = X_OFFSET + CHART_WIDTH + PADDING + LEGEND_WIDTH;
+        let legend_height = Y_OFFSET + num_years as i64 * LEGEND_ROW_HEIGHT;
+        let height = (Y_OFFSET + CHART_HEIGHT + PADDING * 2).max(legend_height);
+        // Round the y-axis up to the next tick, so the top line has some headroom
+        let max_stars = (max_stars / Y_TICK_STEP + 1) * Y_TICK_STEP;
+
+        let mut builder = Self {
+            content: String::new(),
+            width,
+            height,
+            duration: duration.max(1),
+            max_stars,
+        };
+
+        builder.add_header();
+        builder
+    }
+
+    fn x(&self, elapsed: i64) -> i64 {
+        X_OFFSET + elapsed * CHART_WIDTH / self.duration
+    }
+
+    fn y(&self, stars: usize) -> i64 {
+        Y_OFFSET + CHART_HEIGHT - stars as i64 * CHART_HEIGHT / self.max_stars as i64
+    }
+
+    fn add_header(&mut self) {
+        self.content.push_str(&format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#,
+            self.width, self.height, self.width, self.height
+        ));
+        self.content.push_str(
+            r#"
+            <style>"#,
+        );
+        self.content.push_str(THEME_STYLE);
+        self.content.push_str(
+            r#"
+                .title { font-family: Arial; font-size: 14px; font-weight: bold; }
+                .axis-label { font-family: Arial; font-size: 10px; }
+                .legend-label { font-family: Arial; font-size: 12px; }
+                .year-line { fill: none; stroke-width: 2; }
+                .matrix-border { fill: none; stroke-width: 1; }
+                .grid-line { stroke-width: 0.5; stroke-opacity: 0.1; }
+                .text { font-family: Arial; }
+            </style>"#,
+        );
+
+        self.content.push_str(&format!(
+            r#"<text x="{}" y="{}" class="title text" text-anchor="middle">Stars over time</text>"#,
+            X_OFFSET + CHART_WIDTH / 2,
+            Y_OFFSET - PADDING
+        ));
+    }
+
+    fn add_axes(&mut self) {
+        self.content.push_str(&format!(
+            r#"<rect x="{}" y="{}" width="{}" height="{}" class="matrix-border"/>"#,
+            X_OFFSET, Y_OFFSET, CHART_WIDTH, CHART_HEIGHT
+        ));
+
+        for stars in (0..=self.max_stars).step_by(Y_TICK_STEP) {
+            let y = self.y(stars);
+            self.content.push_str(&format!(
+                r#"<line x1="{}" y1="{}" x2="{}" y2="{}" class="grid-line"/>"#,
+                X_OFFSET,
+                y,
+                X_OFFSET + CHART_WIDTH,
+                y
+            ));
+            self.content.push_str(&format!(
+                r#"<text x="{}" y="{}" class="axis-label text" text-anchor="end">{}</text>"#,
+                X_OFFSET - PADDING / 4,
+                y + 3,
+                stars
+            ));
+        }
+
+        let label_y = Y_OFFSET + CHART_HEIGHT + PADDING;
+        for (elapsed, anchor) in [(0, "start"), (self.duration, "end")] {
+            self.content.push_str(&format!(
+                r#"<text x="{}" y="{}" class="axis-label text" text-anchor="{}">Day {}</text>"#,
+                self.x(elapsed),
+                label_y,
+                anchor,
+                elapsed / SECONDS_PER_DAY + 1
+            ));
+        }
+    }
+
+    fn add_year_lines(&mut self, years: &BTreeMap<usize, Vec<i64>>) {
+        for (i, (year, elapsed)) in years.iter().enumerate() {
+            let colour = YEAR_COLOURS[i % YEAR_COLOURS.len()];
+
+            // Draw as steps, each star moves the line straight up at the time it was earned, counted
+            // from when the year started
+            let mut points = vec![format!("{},{}", self.x(0), self.y(0))];
+            for (count, &since_start) in elapsed.iter().enumerate() {
+                let x = self.x(since_start);
+                points.push(format!("{},{}", x, self.y(count)));
+                points.push(format!("{},{}", x, self.y(count + 1)));
+            }
+            points.push(format!(
+                "{},{}",
+                X_OFFSET + CHART_WIDTH,
+                self.y(elapsed.len())
+            ));
+
+            self.content.push_str(&format!(
+                r#"<polyline points="{}" class="year-line" stroke="{}"/>"#,
+                points.join(" "),
+                colour
+            ));
+
+            let legend_x = X_OFFSET + CHART_WIDTH + PADDING;
+            let legend_y = Y_OFFSET + i as i64 * LEGEND_ROW_HEIGHT;
+            self.content.push_str(&format!(
+                r#"<rect x="{}" y="{}" width="10" height="10" fill="{}"/>"#,
+                legend_x, legend_y, colour
+            ));
+            self.content.push_str(&format!(
+                r#"<text x="{}" y="{}" class="legend-label text">{} ({})</text>"#,
+                legend_x + 14,
+                legend_y + 9,
+                year,
+                elapsed.len()
+            ));
+        }
+    }
+
+    fn finalize(mut self) -> String {
+        self.content.push_str("</svg>");
+        self.content
+    }
+}
+
+/// Generate a chart with a line per year showing the cumulative number of stars over time. Each
+/// year is plotted against the time since its first puzzle unlocked, so the years line up.
+pub fn generate_timeline_svg(stars: &[StarTimestamp]) -> String {
+    let mut years: BTreeMap<usize, Vec<i64>> = BTreeMap::new();
+    for star in stars {
+        // Stars can't be earned before the year starts, but don't draw off the chart if they are
+        let elapsed = (star.timestamp - year_start(star.year)).max(0);
+        years.entry(star.year).or_default().push(elapsed);
+    }
+    for elapsed in years.values_mut() {
+        elapsed.sort_unstable();
+    }
+
+    let duration = years.values().filter_map(|e| e.last()).max().copied();
+    let max_stars = years.values().map(Vec::len).max().unwrap_or(0);
+
+    let mut builder = TimelineBuilder::new(duration.unwrap_or(0), max_stars, years.len());
+    builder.add_axes();
+    builder.add_year_lines(&years);
+    builder.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEADERBOARD: &str = r#"{
+        "event": "2024",
+        "owner_id": 1,
+        "members": {
+            "1": {
+                "id": 1,
+                "name": "alice",
+                "stars": 3,
+                "completion_day_level": {
+                    "1": {
+                        "1": { "get_star_ts": 1733029200, "star_index": 0 },
+                        "2": { "get_star_ts": 1733030000, "star_index": 1 }
+                    },
+                    "2": {
+                        "1": { "get_star_ts": 1733115600, "star_index": 2 }
+                    }
+                }
+            },
+            "2": { "id": 2, "name": null, "stars": 0, "completion_day_level": {} }
+        }
+    }"#;
+
+    #[test]
+    fn test_parse_leaderboard() {
+        let stars = parse_leaderboard(LEADERBOARD, Some("alice")).unwrap();
+        assert_eq!(stars.len(), 3);
+        assert_eq!(
+            stars[0],
+            StarTimestamp {
+                year: 2024,
+                day: 1,
+                part: 1,
+                timestamp: 1733029200
+            }
+        );
+        assert_eq!(stars[2].day, 2);
+
+        assert_eq!(parse_leaderboard(LEADERBOARD, Some("2")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_parse_leaderboard_member_errors() {
+        assert!(matches!(
+            parse_leaderboard(LEADERBOARD, None),
+            Err(TimelineError::AmbiguousMember { count: 2 })
+        ));
+        assert!(matches!(
+            parse_leaderboard(LEADERBOARD, Some("bob")),
+            Err(TimelineError::MemberNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_year_start() {
+        assert_eq!(year_start(2024), 1733029200);
+        assert_eq!(year_start(2023), 1701406800);
+        assert_eq!(year_start(2015), 1448946000);
+    }
+
+    #[test]
+    fn test_timeline_svg() {
+        let mut stars = parse_leaderboard(LEADERBOARD, Some("alice")).unwrap();
+        stars.push(StarTimestamp {
+            year: 2023,
+            day: 1,
+            part: 1,
+            timestamp: 1701410000,
+        });
+        let svg = generate_timeline_svg(&stars);
+
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.ends_with("</svg>"));
+        assert!(svg.contains("prefers-color-scheme: dark"));
+        assert_eq!(svg.matches("<polyline").count(), 2);
+        assert!(svg.contains("2024 (3)"));
+        assert!(svg.contains("2023 (1)"));
+        assert!(svg.contains(">Day 1<"));
+        assert!(svg.contains(">Day 2<"));
+
+        // Both years start at the left edge, however many years apart they are
+        assert_eq!(svg.matches(r#"points="50,250 "#).count(), 2);
+    }
+
+    #[test]
+    fn test_empty_timeline() {
+        let svg = generate_timeline_svg(&[]);
+        assert!(svg.starts_with("<svg"));
+        assert!(!svg.contains("<polyline"));
+    }
+}